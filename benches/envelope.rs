@@ -0,0 +1,77 @@
+// Compares forwarding an envelope through an inbox the old way (one frame holding
+// both addresses and payload, reassembled on every hop) with the multipart layout,
+// where the payload frame is handed from socket to socket untouched.
+//
+//   cargo bench --bench envelope
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use yocto_actor::{Address, AddressType, Envelope, Inbox, Outbox, ShouldBlock};
+
+const PAYLOAD_SIZES: [usize; 3] = [64, 64 * 1024, 1024 * 1024];
+
+// Addresses were zero-padded to this many bytes in the single frame layout
+const LEGACY_ADDRESS_LENGTH: usize = 32;
+
+// Builds a frame the way envelopes were laid out before the multipart
+// layout: [payload][source][destination], both addresses zero-padded
+fn legacy_frame(mut payload: Vec<u8>, dest_address: &Address, source_address: &Address) -> Vec<u8> {
+    for address in [source_address, dest_address].iter() {
+        let address = address.as_str().as_bytes();
+        assert!(address.len() <= LEGACY_ADDRESS_LENGTH, "Address too long");
+        payload.extend_from_slice(address);
+        payload.resize(payload.len() + LEGACY_ADDRESS_LENGTH - address.len(), 0);
+    }
+    payload
+}
+
+fn forward_envelope(c: &mut Criterion) {
+    let ctx = zmq::Context::new();
+
+    let source_address = Address::new(AddressType::Local);
+    let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+    let outbox = Outbox::new(ctx.clone(), inbox.address(), &source_address);
+
+    let single_frame_socket = ctx.socket(zmq::PUSH).expect("Cannot create socket");
+    single_frame_socket
+        .connect(inbox.address().as_str())
+        .expect("Cannot connect socket");
+
+    let mut group = c.benchmark_group("forward_envelope");
+    for &size in PAYLOAD_SIZES.iter() {
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("single_frame", size), &size, |b, &size| {
+            let mut bytes = legacy_frame(vec![0u8; size], inbox.address(), &source_address);
+
+            b.iter(|| {
+                single_frame_socket
+                    .send(&bytes, 0)
+                    .expect("Cannot send envelope");
+
+                let received = inbox
+                    .receive(ShouldBlock::from(true))
+                    .expect("Cannot receive envelope");
+                let (_, _, payload) = Envelope::from(received).open();
+                bytes = legacy_frame(payload, inbox.address(), &source_address);
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("multipart", size), &size, |b, &size| {
+            let mut envelope = Some(Envelope::new(
+                vec![0u8; size],
+                inbox.address(),
+                &source_address,
+            ));
+
+            b.iter(|| {
+                outbox.send_envelope(envelope.take().expect("Envelope went missing"));
+
+                envelope = inbox.receive_envelope(ShouldBlock::from(true));
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, forward_envelope);
+criterion_main!(benches);
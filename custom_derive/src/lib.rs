@@ -4,32 +4,154 @@ extern crate proc_macro2;
 use heck::SnakeCase;
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use std::collections::HashMap;
+use syn::parse::{ParseStream, Parser};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, DeriveInput, Token};
+
+// Names the handler arguments of a tuple variant, e.g.
+// `#[handler_args(spec)] Job(JobSpec)` generates `handle_job(&mut self, spec: JobSpec)`.
+// Without it the arguments are called arg0, arg1 and so on.
+const HANDLER_ARGS_ATTRIBUTE: &str = "handler_args";
+
+// Set through #[actor_message(option, ...)]
+#[derive(Default)]
+struct Options {
+    // Handlers and run are async fns and messages come from an AsyncInbox
+    asynchronous: bool,
+    // Handlers take a `ctx: &Context` first argument describing the envelope
+    context: bool,
+    // Handlers return Result<ShouldTerminate, error> and failures go to on_error.
+    // The enum has to be Clone then: handlers consume the message, so run keeps
+    // a copy of each one to hand to on_error. So does the error, run keeps a
+    // copy of a failure for pre_restart and to leave try_run with.
+    error: Option<syn::Type>,
+}
+
+impl Options {
+    fn parse(attr: TokenStream) -> syn::Result<Self> {
+        let parser = |input: ParseStream| {
+            let mut options = Self::default();
+
+            while !input.is_empty() {
+                // async is a keyword, so it does not parse as an Ident
+                if input.peek(Token![async]) {
+                    input.parse::<Token![async]>()?;
+                    options.asynchronous = true;
+                    if !input.is_empty() {
+                        input.parse::<Token![,]>()?;
+                    }
+                    continue;
+                }
+
+                let option: Ident = input.parse()?;
+                match option.to_string().as_str() {
+                    "context" => options.context = true,
+                    "error" => {
+                        input.parse::<Token![=]>()?;
+                        options.error = Some(input.parse()?);
+                    }
+                    _ => {
+                        return Err(syn::Error::new(
+                            option.span(),
+                            format!(
+                                "unknown actor_message option {}, expected async, context or error = Type",
+                                option
+                            ),
+                        ))
+                    }
+                }
+
+                if !input.is_empty() {
+                    input.parse::<Token![,]>()?;
+                }
+            }
+
+            Ok(options)
+        };
+
+        parser.parse2(attr)
+    }
+}
 
 #[proc_macro_attribute]
 pub fn actor_message(
-    _attr: proc_macro::TokenStream,
+    attr: proc_macro::TokenStream,
     item: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
     let input = parse_macro_input!(item as DeriveInput);
+
+    // Errors are reported as compile errors pointing at the offending tokens,
+    // panicking here would only tell the user that the macro crashed.
+    // The type itself is still emitted, so that its uses do not fail as well.
+    let expanded = expand(attr.into(), &input).unwrap_or_else(|err| {
+        let stripped_input = strip_handler_args(&input);
+        let mut expanded = err.to_compile_error();
+        expanded.extend(quote!(#stripped_input));
+        expanded
+    });
+    proc_macro::TokenStream::from(expanded)
+}
+
+fn expand(attr: TokenStream, input: &DeriveInput) -> syn::Result<TokenStream> {
+    let options = Options::parse(attr)?;
+    let (context_parameter, context_argument) = if options.context {
+        (quote!(ctx: &::yocto_actor::Context,), quote!(ctx,))
+    } else {
+        (TokenStream::new(), TokenStream::new())
+    };
+    let (asyncness, awaited, inbox_type, outbox_type) = if options.asynchronous {
+        (
+            quote!(async),
+            quote!(.await),
+            quote!(::yocto_actor::AsyncInbox),
+            quote!(::yocto_actor::AsyncOutbox),
+        )
+    } else {
+        (
+            TokenStream::new(),
+            TokenStream::new(),
+            quote!(::yocto_actor::Inbox),
+            quote!(::yocto_actor::Outbox),
+        )
+    };
+    let handler_return = match &options.error {
+        Some(error_type) => {
+            quote!(::std::result::Result<::yocto_actor::ShouldTerminate, #error_type>)
+        }
+        None => quote!(::yocto_actor::ShouldTerminate),
+    };
+
     // get the name of the type we want to implement the trait for
     let enum_name = &input.ident;
     // eprintln!("[yocto_actor][actor_message] enum name: {}", enum_name);
 
     let mut expanded = TokenStream::new();
 
-    let enum_data = if let syn::Data::Enum(data) = &input.data {
-        data
-    } else {
-        panic!("[yocto_actor][actor_message] {} is not an enum", enum_name);
+    let enum_data = match &input.data {
+        syn::Data::Enum(data) => data,
+        syn::Data::Struct(data) => return Err(not_an_enum(data.struct_token.span, enum_name)),
+        syn::Data::Union(data) => return Err(not_an_enum(data.union_token.span, enum_name)),
     };
 
+    // The handler trait and the client take the same generic parameters as the enum
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let enum_type = quote!(#enum_name #ty_generics);
+
     let trait_name = Ident::new(&format!("{}Handler", &enum_name), Span::call_site());
+    let receive_trait_name = Ident::new(&format!("{}Receive", &enum_name), Span::call_site());
+    let client_name = Ident::new(&format!("{}Client", &enum_name), Span::call_site());
 
     // eprintln!("[yocto_actor][actor_message] trait name: {}", trait_name);
 
     let mut dispatch_arms = TokenStream::new();
+    let mut variant_name_arms = TokenStream::new();
     let mut handler_prototypes = TokenStream::new();
+    let mut client_methods = TokenStream::new();
+
+    let mut errors = Errors::default();
+    // Snake-casing may map different variants onto the same handler
+    let mut handler_variants: HashMap<String, &Ident> = HashMap::new();
 
     for variant_data in &enum_data.variants {
         let variant_name = &variant_data.ident;
@@ -37,6 +159,33 @@ pub fn actor_message(
             &format!("handle_{}", &variant_name).to_snake_case(),
             Span::call_site(),
         );
+        let send_method_name = Ident::new(
+            &format!("send_{}", &variant_name).to_snake_case(),
+            Span::call_site(),
+        );
+        let variant_label = variant_name.to_string();
+        variant_name_arms.extend(quote!(#enum_name::#variant_name { .. } => #variant_label,));
+
+        if let Some(other_variant) =
+            handler_variants.insert(handler_method_name.to_string(), variant_name)
+        {
+            errors.push(syn::Error::new(
+                variant_name.span(),
+                format!(
+                    "{} and {} would both be handled by {}, please rename one of them",
+                    other_variant, variant_name, handler_method_name
+                ),
+            ));
+        }
+
+        if !matches!(variant_data.fields, syn::Fields::Unnamed(_)) {
+            for attr in handler_args_attributes(variant_data) {
+                errors.push(syn::Error::new_spanned(
+                    attr,
+                    format!("{} only applies to tuple variants", HANDLER_ARGS_ATTRIBUTE),
+                ));
+            }
+        }
 
         // eprintln!(
         //     "[yocto_actor][actor_message] found variant {}, handler function name: {}",
@@ -47,17 +196,57 @@ pub fn actor_message(
             syn::Fields::Unit => {
                 // eprintln!("[yocto_actor][actor_message] variant type: unit");
                 let current_arm = quote! (
-                    #enum_name::#variant_name => self. #handler_method_name(),
+                    #enum_name::#variant_name => self. #handler_method_name(#context_argument) #awaited,
                 );
                 // eprintln!("[yocto_actor][actor_message] Current arm: {}", &current_arm);
                 dispatch_arms.extend(current_arm);
                 handler_prototypes.extend(quote! {
-                    fn #handler_method_name(&mut self) -> ShouldTerminate;
+                    #asyncness fn #handler_method_name(&mut self, #context_parameter) -> #handler_return;
+                });
+                client_methods.extend(quote! {
+                    pub fn #send_method_name(&self) {
+                        let message: #enum_type = #enum_name::#variant_name;
+                        self.outbox.send_message(&message);
+                    }
                 });
             }
-            syn::Fields::Unnamed(_unnamed) => {
+            syn::Fields::Unnamed(unnamed_fields) => {
                 // eprintln!("[yocto_actor][actor_message] variant type: unnamed");
-                unimplemented!("Tuple variants are not supported") // ToDo
+
+                let argument_names = match handler_argument_names(variant_data, unnamed_fields) {
+                    Ok(argument_names) => argument_names,
+                    Err(err) => {
+                        errors.push(err);
+                        continue;
+                    }
+                };
+
+                let mut handler_arguments = TokenStream::new();
+                let mut destructured_fields = TokenStream::new();
+
+                for (field, argument_name) in unnamed_fields.unnamed.iter().zip(&argument_names) {
+                    if options.context && argument_name == "ctx" {
+                        errors.push(context_clash(argument_name));
+                    }
+                    let field_type = &field.ty;
+                    destructured_fields.extend(quote!(#argument_name,));
+                    handler_arguments.extend(quote! (#argument_name : #field_type,));
+                }
+
+                let current_arm = quote! (
+                    #enum_name::#variant_name( #destructured_fields ) => self. #handler_method_name(#context_argument #destructured_fields) #awaited,
+                );
+                dispatch_arms.extend(current_arm);
+
+                handler_prototypes.extend(quote! {
+                    #asyncness fn #handler_method_name(&mut self, #context_parameter #handler_arguments) -> #handler_return;
+                });
+                client_methods.extend(quote! {
+                    pub fn #send_method_name(&self, #handler_arguments) {
+                        let message: #enum_type = #enum_name::#variant_name( #destructured_fields );
+                        self.outbox.send_message(&message);
+                    }
+                });
             }
             syn::Fields::Named(named_fields) => {
                 // eprintln!("[yocto_actor][actor_message] variant type: named");
@@ -67,6 +256,9 @@ pub fn actor_message(
 
                 for field in named_fields.named.iter() {
                     let field_name = &field.ident.as_ref().expect("expected a named field");
+                    if options.context && *field_name == "ctx" {
+                        errors.push(context_clash(field_name));
+                    }
                     let field_type = &field.ty;
                     // eprintln!(
                     //     "[yocto_actor][actor_message] Found named field: name {}, type {}",
@@ -78,43 +270,453 @@ pub fn actor_message(
                 }
 
                 let current_arm = quote! (
-                    #enum_name::#variant_name{ #destructured_fields } => self. #handler_method_name(#destructured_fields),
+                    #enum_name::#variant_name{ #destructured_fields } => self. #handler_method_name(#context_argument #destructured_fields) #awaited,
                 );
                 // eprintln!("[yocto_actor][actor_message] Current arm: {}", &current_arm);
                 dispatch_arms.extend(current_arm);
 
                 handler_prototypes.extend(quote! {
-                    fn #handler_method_name(&mut self, #handler_arguments) -> ShouldTerminate;
+                    #asyncness fn #handler_method_name(&mut self, #context_parameter #handler_arguments) -> #handler_return;
+                });
+                client_methods.extend(quote! {
+                    pub fn #send_method_name(&self, #handler_arguments) {
+                        let message: #enum_type = #enum_name::#variant_name{ #destructured_fields };
+                        self.outbox.send_message(&message);
+                    }
                 });
             }
         };
     }
 
+    errors.finish()?;
+
+    let stripped_input = strip_handler_args(input);
+
+    // The default receive needs to check the type tag and deserialize the enum
+    let mut trait_generics = input.generics.clone();
+    trait_generics.make_where_clause().predicates.push(
+        syn::parse_quote!(#enum_type: ::yocto_actor::Message + ::serde::de::DeserializeOwned),
+    );
+    let type_tag = proc_macro2::Literal::u64_suffixed(type_tag(enum_name, enum_data));
+    let instance_type_tag = instance_type_tag(&input.generics);
+    if let Some(error_type) = &options.error {
+        // on_error gets a copy of the message, the handler consumes the original
+        trait_generics
+            .make_where_clause()
+            .predicates
+            .push(syn::parse_quote!(#enum_type: ::std::clone::Clone));
+        // pre_restart and try_run need the error on_error was handed
+        trait_generics
+            .make_where_clause()
+            .predicates
+            .push(syn::parse_quote!(#error_type: ::std::clone::Clone));
+    }
+    let trait_where_clause = &trait_generics.where_clause;
+
+    // Every handler gets the receive trait, so it cannot be implemented by hand
+    let mut receive_generics = input.generics.clone();
+    receive_generics
+        .params
+        .push(syn::parse_quote!(__Actor: #trait_name #ty_generics + ?Sized));
+    let (receive_impl_generics, _, _) = receive_generics.split_for_impl();
+
+    // Sending needs the enum to be a Message, which depends on its parameters
+    let mut client_generics = input.generics.clone();
+    client_generics
+        .make_where_clause()
+        .predicates
+        .push(syn::parse_quote!(#enum_type: ::yocto_actor::Message));
+    let client_where_clause = &client_generics.where_clause;
+    let visibility = &input.vis;
+
+    let handler_call = if options.context {
+        quote!(self.dispatch_message(message, &ctx) #awaited)
+    } else {
+        quote!(self.dispatch_message(message) #awaited)
+    };
+    if enum_data.variants.is_empty() {
+        variant_name_arms.extend(quote!(_ => unreachable!(),));
+    }
+    // Handlers consume the message, so after_dispatch is told its variant instead
+    let dispatch_call = quote! {{
+        let variant = match &message {
+            #variant_name_arms
+        };
+        self.before_dispatch(&message);
+        let outcome = #handler_call;
+        self.after_dispatch(variant, &outcome);
+        outcome
+    }};
+    // In error mode dispatch results in the error on_error stopped at, which
+    // try_run leaves with and run_turn drops
+    let (error_methods, dispatch, run_settled, turn_settled) = match &options.error {
+        Some(error_type) => (
+            quote! {
+                // Decides how run goes on after a handler failed on the message,
+                // which is a copy taken before dispatch, hence the Clone bound
+                fn on_error(&mut self, err: #error_type, message: &#enum_type) -> ::yocto_actor::ErrorAction;
+
+                // Called around on_restart when on_error asked for a restart
+                fn pre_restart(&mut self, _err: &#error_type) {}
+                fn on_restart(&mut self) {}
+                fn post_restart(&mut self) {}
+            },
+            quote! {{
+                let dispatched = ::std::clone::Clone::clone(&message);
+                match #dispatch_call {
+                    Ok(should_terminate) => Ok(should_terminate),
+                    Err(err) => {
+                        let failure = ::std::clone::Clone::clone(&err);
+                        match self.on_error(err, &dispatched) {
+                            ::yocto_actor::ErrorAction::Continue => Ok(::yocto_actor::ShouldTerminate::from(false)),
+                            ::yocto_actor::ErrorAction::Stop => Err(failure),
+                            ::yocto_actor::ErrorAction::Restart => {
+                                self.pre_restart(&failure);
+                                self.on_restart();
+                                self.post_restart();
+                                Ok(::yocto_actor::ShouldTerminate::from(false))
+                            }
+                        }
+                    }
+                }
+            }},
+            quote! {
+                match handled {
+                    Ok(should_terminate) => should_terminate,
+                    Err(err) => {
+                        failure = Some(err);
+                        ::yocto_actor::ShouldTerminate::from(true)
+                    }
+                }
+            },
+            quote! {
+                match handled {
+                    Ok(should_terminate) => should_terminate,
+                    Err(_) => ::yocto_actor::ShouldTerminate::from(true),
+                }
+            },
+        ),
+        None => (
+            TokenStream::new(),
+            dispatch_call,
+            quote!(handled),
+            quote!(handled),
+        ),
+    };
+
+    // Once a drain request arrived run goes on without waiting, and leaves
+    // as soon as nothing is left in the inbox
+    let stopped_arms = quote! {
+        None if draining => ::yocto_actor::ShouldTerminate::from(true),
+        None if self.inbox().drain_requested() => {
+            draining = true;
+            ::yocto_actor::ShouldTerminate::from(false)
+        }
+        None if self.inbox().stop_requested() => ::yocto_actor::ShouldTerminate::from(true),
+    };
+
+    let envelope_context = if options.asynchronous {
+        quote!(self.inbox().context(&envelope))
+    } else {
+        quote!(::yocto_actor::Context::new(self.inbox(), &envelope))
+    };
+    let (context_methods, context_receive_methods, receive_next, received_pattern, headers_call) =
+        if options.context {
+            (
+                quote! {
+                    // This is what run uses, see receive_next
+                    #asyncness fn receive_next_with_context(
+                        &self,
+                        timeout: Option<::std::time::Duration>,
+                    ) -> Option<(#enum_type, ::yocto_actor::Context)> {
+                        let (message, envelope) = self.receive_accepted(timeout) #awaited?;
+                        Some((message, #envelope_context))
+                    }
+                },
+                quote! {
+                    #asyncness fn receive_with_context(&self) -> (#enum_type, ::yocto_actor::Context) {
+                        self.receive_next_with_context(None)
+                            #awaited
+                            .expect("Actor failed to receive message")
+                    }
+
+                    // Returns None if nothing arrived in time
+                    #asyncness fn receive_with_context_timeout(
+                        &self,
+                        timeout: ::std::time::Duration,
+                    ) -> Option<(#enum_type, ::yocto_actor::Context)> {
+                        self.receive_next_with_context(Some(timeout)) #awaited
+                    }
+                },
+                quote!(receive_next_with_context),
+                quote!(Some((message, ctx))),
+                quote!(self.on_headers(ctx.headers());),
+            )
+        } else {
+            (
+                TokenStream::new(),
+                TokenStream::new(),
+                quote!(receive_next),
+                quote!(Some((message, headers))),
+                quote!(self.on_headers(&headers);),
+            )
+        };
+    // With error = ..., run is a wrapper of try_run, which tells a failure
+    // from the actor being asked to stop
+    let (run_name, run_return, failure_declaration, run_result, run_wrapper) = match &options.error
+    {
+        Some(error_type) => (
+            quote!(try_run),
+            quote!(-> ::std::result::Result<(), #error_type>),
+            quote!(let mut failure = None;),
+            quote! {
+                match failure {
+                    Some(err) => Err(err),
+                    None => Ok(()),
+                }
+            },
+            quote! {
+                // Like try_run, for actors whose failures are no one else's business
+                #asyncness fn run(&mut self) {
+                    let _ = self.try_run() #awaited;
+                }
+            },
+        ),
+        None => (
+            quote!(run),
+            TokenStream::new(),
+            TokenStream::new(),
+            TokenStream::new(),
+            TokenStream::new(),
+        ),
+    };
+    let received_arm = |settled: &TokenStream| {
+        quote! {
+            #received_pattern => {
+                #headers_call
+                let handled = #dispatch;
+                #settled
+            }
+        }
+    };
+    let run_received_arm = received_arm(&run_settled);
+    let turn_received_arm = received_arm(&turn_settled);
+    // Async actors have the runtime to share threads with
+    let turn_method = if options.asynchronous {
+        TokenStream::new()
+    } else {
+        quote! {
+            // One turn of run that does not wait for messages, for a Scheduler
+            // running many actors on a few threads. Returns None if nothing was
+            // queued, otherwise whether the actor is done, in which case
+            // post_stop has been called. idle_timeout is not used.
+            //
+            // Once a drain request is received, the turn handles everything
+            // still queued before returning, regardless of the scheduler's
+            // throughput. The request has been taken off the inbox by then, so
+            // nothing would wake the actor for the rest of the queue otherwise.
+            fn run_turn(&mut self) -> Option<::yocto_actor::ShouldTerminate> {
+                let mut draining = false;
+                loop {
+                    self.pre_run();
+
+                    let received = self.#receive_next(Some(::std::time::Duration::ZERO));
+                    let idle = received.is_none() && !self.inbox().stop_requested();
+                    let should_terminate = match received {
+                        #turn_received_arm
+                        #stopped_arms
+                        None => ::yocto_actor::ShouldTerminate::from(false),
+                    };
+
+                    if should_terminate.into() {
+                        self.post_stop();
+                        return Some(::yocto_actor::ShouldTerminate::from(true));
+                    }
+
+                    self.post_run();
+
+                    if idle {
+                        return None;
+                    }
+                    if !draining {
+                        return Some(::yocto_actor::ShouldTerminate::from(false));
+                    }
+                }
+            }
+        }
+    };
+    // Async traits are for use within the crate defining the actor, whose
+    // futures are then known to be Send or not
+    let trait_attributes = if options.asynchronous {
+        quote!(#[allow(async_fn_in_trait)])
+    } else {
+        TokenStream::new()
+    };
+
     expanded.extend(quote! {
-        #input
+        #stripped_input
+
+        impl #impl_generics ::yocto_actor::Message for #enum_type #where_clause {
+            const TYPE_TAG: Option<u64> = Some(#type_tag);
 
-        impl Message for #enum_name {}
+            #instance_type_tag
+        }
+
+        #trait_attributes
+        pub trait #trait_name #impl_generics #trait_where_clause {
+            // Called once when run starts, before anything is received
+            fn pre_start(&mut self) {}
 
-        pub trait #trait_name {
+            // Called around each turn of the run loop, whether a message
+            // arrived or not
             fn pre_run(&mut self) {}
             fn post_run(&mut self) {}
 
-            fn receive(&self) -> #enum_name;
+            // Called around the handler of every message, with the message
+            // before and with the name of its variant and what the handler
+            // returned after
+            fn before_dispatch(&mut self, _message: &#enum_type) {}
+            fn after_dispatch(&mut self, _variant: &'static str, _outcome: &#handler_return) {}
+
+            // Where the default receive methods take messages from
+            fn inbox(&self) -> &#inbox_type;
+
+            // Override for custom decoding, the default uses the codec the sender named
+            fn decode(&self, envelope: &::yocto_actor::Envelope) -> ::yocto_actor::Result<#enum_type> {
+                envelope.try_decode()
+            }
+
+            // This is what run uses, so actors taking messages from somewhere
+            // other than inbox override this one. Returns None if nothing arrived
+            // in time or the actor was asked to stop, waits forever without timeout.
+            #asyncness fn receive_next(
+                &self,
+                timeout: Option<::std::time::Duration>,
+            ) -> Option<(#enum_type, ::yocto_actor::Headers)> {
+                let (message, envelope) = self.receive_accepted(timeout) #awaited?;
+                Some((message, envelope.headers()))
+            }
+
+            // Called with the headers of each message right before it is dispatched
+            fn on_headers(&mut self, _headers: &::yocto_actor::Headers) {}
+
+            // How long run waits for a message before calling on_idle, forever if None
+            fn idle_timeout(&self) -> Option<::std::time::Duration> {
+                None
+            }
+
+            // Where envelopes tagged as another message type or failing to
+            // decode go instead of being dispatched. They are dropped if there
+            // is none.
+            fn dead_letters(&self) -> Option<&#outbox_type> {
+                None
+            }
+
+            // Called with envelopes tagged as another message type and the
+            // Error::ProtocolMismatch saying which. Passes them on to dead_letters,
+            // override to log or count them as well.
+            #asyncness fn on_protocol_mismatch(
+                &self,
+                _err: ::yocto_actor::Error,
+                envelope: ::yocto_actor::Envelope,
+            ) {
+                if let Some(dead_letters) = self.dead_letters() {
+                    let _ = dead_letters.try_send_envelope(envelope) #awaited;
+                }
+            }
 
-            fn run(&mut self) {
+            // Takes envelopes from inbox until one carries our message type and
+            // decodes. Returns None if nothing arrived in time or a stop request
+            // did. Envelopes that cannot be read at all are dropped, a bad packet
+            // must not take the actor down.
+            #asyncness fn receive_accepted(
+                &self,
+                timeout: Option<::std::time::Duration>,
+            ) -> Option<(#enum_type, ::yocto_actor::Envelope)> {
+                let deadline = timeout.map(|timeout| ::std::time::Instant::now() + timeout);
+                loop {
+                    let received = match deadline {
+                        Some(deadline) => self
+                            .inbox()
+                            .try_receive_envelope_timeout(
+                                deadline.saturating_duration_since(::std::time::Instant::now()),
+                            )
+                            #awaited
+                            .map(Some),
+                        None => self
+                            .inbox()
+                            .try_receive_envelope(::yocto_actor::ShouldBlock::from(true))
+                            #awaited,
+                    };
+                    let envelope = match received {
+                        Ok(Some(envelope)) => envelope,
+                        Ok(None)
+                        | Err(::yocto_actor::Error::Timeout)
+                        | Err(::yocto_actor::Error::Stopped) => return None,
+                        Err(::yocto_actor::Error::MalformedEnvelope(_)) => continue,
+                        Err(err) => panic!("Actor failed to receive message: {}", err),
+                    };
+
+                    if let Err(err) = envelope.try_check_type_tag::<#enum_type>() {
+                        self.on_protocol_mismatch(err, envelope) #awaited;
+                        continue;
+                    }
+                    // Headers are checked as well, so that handing them out later cannot fail
+                    if let (Ok(message), Ok(_)) = (self.decode(&envelope), envelope.try_headers()) {
+                        return Some((message, envelope));
+                    }
+                    if let Some(dead_letters) = self.dead_letters() {
+                        // Whatever cannot even be readdressed is dropped
+                        let _ = dead_letters.try_send_envelope(envelope) #awaited;
+                    }
+                }
+            }
+
+            #context_methods
+
+            #error_methods
+
+            #asyncness fn on_idle(&mut self) -> ::yocto_actor::ShouldTerminate {
+                ::yocto_actor::ShouldTerminate::from(false)
+            }
+
+            // Called once run is left, be it for a handler or a stop request
+            fn post_stop(&mut self) {}
+
+            #asyncness fn #run_name(&mut self) #run_return {
+                self.pre_start();
+                let mut draining = false;
+                #failure_declaration
                 loop {
                     self.pre_run();
 
-                    let message = self.receive();
-                    if self.dispatch_message(message).into() {
+                    let timeout = if draining {
+                        Some(::std::time::Duration::ZERO)
+                    } else {
+                        self.idle_timeout()
+                    };
+                    let received = self.#receive_next(timeout) #awaited;
+                    let should_terminate = match received {
+                        #run_received_arm
+                        #stopped_arms
+                        None => self.on_idle() #awaited,
+                    };
+
+                    if should_terminate.into() {
                         break;
                     }
 
                     self.post_run();
                 }
+                self.post_stop();
+                #run_result
             }
 
-            fn dispatch_message(&mut self, message: #enum_name) -> ShouldTerminate {
+            #run_wrapper
+
+            #turn_method
+
+            #asyncness fn dispatch_message(&mut self, message: #enum_type, #context_parameter) -> #handler_return {
                 match message {
                     #dispatch_arms
                 }
@@ -122,7 +724,236 @@ pub fn actor_message(
 
             #handler_prototypes
         }
+
+        // Receives for actors that drive their own loop instead of calling run.
+        // They live outside the handler trait, since run takes messages through
+        // receive_next and would never see an override of them.
+        #trait_attributes
+        pub trait #receive_trait_name #impl_generics: #trait_name #ty_generics #trait_where_clause {
+            #asyncness fn receive(&self) -> #enum_type {
+                self.receive_with_headers() #awaited .0
+            }
+
+            #asyncness fn receive_with_headers(&self) -> (#enum_type, ::yocto_actor::Headers) {
+                self.receive_next(None)
+                    #awaited
+                    .expect("Actor failed to receive message")
+            }
+
+            // Returns None if nothing arrived in time
+            #asyncness fn receive_timeout(
+                &self,
+                timeout: ::std::time::Duration,
+            ) -> Option<(#enum_type, ::yocto_actor::Headers)> {
+                self.receive_next(Some(timeout)) #awaited
+            }
+
+            #context_receive_methods
+        }
+
+        impl #receive_impl_generics #receive_trait_name #ty_generics for __Actor #trait_where_clause {}
+
+        // Sends the variants as plain method calls to an actor handling them
+        #visibility struct #client_name #impl_generics #where_clause {
+            outbox: ::yocto_actor::Outbox<#enum_type>,
+        }
+
+        impl #impl_generics #client_name #ty_generics #client_where_clause {
+            pub fn new(outbox: ::yocto_actor::Outbox<#enum_type>) -> Self {
+                Self { outbox }
+            }
+
+            // For headers, asks and error handling
+            pub fn outbox(&self) -> &::yocto_actor::Outbox<#enum_type> {
+                &self.outbox
+            }
+
+            #client_methods
+        }
+
+        impl #impl_generics ::std::convert::From<::yocto_actor::Outbox<#enum_type>> for #client_name #ty_generics #where_clause {
+            fn from(outbox: ::yocto_actor::Outbox<#enum_type>) -> Self {
+                Self::new(outbox)
+            }
+        }
     });
     // eprintln!("[yocto_actor][actor_message] final result: {}", expanded);
-    proc_macro::TokenStream::from(expanded)
+    Ok(expanded)
+}
+
+// Hashes the enum name and the names and types of all variant fields with
+// 64 bit FNV-1a, which unlike std's hashers is guaranteed to stay the same.
+// Instances of a generic enum share it, since only the declaration is seen,
+// see instance_type_tag.
+fn type_tag(enum_name: &Ident, enum_data: &syn::DataEnum) -> u64 {
+    let mut layout = enum_name.to_string();
+    for variant_data in &enum_data.variants {
+        layout.push_str(&format!("|{}", variant_data.ident));
+        match &variant_data.fields {
+            syn::Fields::Unit => {}
+            syn::Fields::Unnamed(_) => layout.push('('),
+            syn::Fields::Named(_) => layout.push('{'),
+        }
+        for field in variant_data.fields.iter() {
+            if let Some(field_name) = &field.ident {
+                layout.push_str(&format!("{}:", field_name));
+            }
+            let field_type = &field.ty;
+            layout.push_str(&quote!(#field_type).to_string());
+            layout.push(',');
+        }
+    }
+    // Token spacing is up to the compiler, so it does not count
+    layout.retain(|c| !c.is_whitespace());
+
+    layout.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Mixes the type and const parameters of a generic enum into its tag at
+// runtime, so that e.g. Command<String> and Command<u32> do not pass for one
+// another. type_name is only stable within one compiler version, so peers
+// exchanging generic messages have to be built with the same one.
+fn instance_type_tag(generics: &syn::Generics) -> TokenStream {
+    let mut parameter_names = Vec::new();
+    for parameter in &generics.params {
+        match parameter {
+            syn::GenericParam::Type(type_param) => {
+                let ident = &type_param.ident;
+                parameter_names.push(quote!(::std::any::type_name::<#ident>()));
+            }
+            syn::GenericParam::Const(const_param) => {
+                let ident = &const_param.ident;
+                parameter_names.push(quote!(#ident.to_string().as_str()));
+            }
+            syn::GenericParam::Lifetime(_) => {}
+        }
+    }
+    if parameter_names.is_empty() {
+        return TokenStream::new();
+    }
+
+    quote! {
+        fn type_tag() -> Option<u64> {
+            // Continues the FNV-1a hash of TYPE_TAG, each name followed by a comma
+            let mix = |hash: u64, name: &str| {
+                name.bytes().chain(Some(b',')).fold(hash, |hash, byte| {
+                    (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+                })
+            };
+            let mut hash = Self::TYPE_TAG?;
+            #(hash = mix(hash, #parameter_names);)*
+            Some(hash)
+        }
+    }
+}
+
+// handler_args is ours, the compiler would not know what to do with it
+fn strip_handler_args(input: &DeriveInput) -> DeriveInput {
+    let mut stripped_input = input.clone();
+    if let syn::Data::Enum(data) = &mut stripped_input.data {
+        for variant_data in data.variants.iter_mut() {
+            variant_data
+                .attrs
+                .retain(|attr| !attr.path.is_ident(HANDLER_ARGS_ATTRIBUTE));
+        }
+    }
+
+    stripped_input
+}
+
+fn context_clash(name: &Ident) -> syn::Error {
+    syn::Error::new(
+        name.span(),
+        "ctx is taken by the handler context, please pick another name",
+    )
+}
+
+fn not_an_enum(span: Span, name: &Ident) -> syn::Error {
+    syn::Error::new(
+        span,
+        format!(
+            "actor_message can only be applied to enums, {} is not one",
+            name
+        ),
+    )
+}
+
+// Collects every problem with the input, so that they are all reported at once
+#[derive(Default)]
+struct Errors(Option<syn::Error>);
+
+impl Errors {
+    fn push(&mut self, err: syn::Error) {
+        match &mut self.0 {
+            Some(errors) => errors.combine(err),
+            None => self.0 = Some(err),
+        }
+    }
+
+    fn finish(self) -> syn::Result<()> {
+        match self.0 {
+            Some(errors) => Err(errors),
+            None => Ok(()),
+        }
+    }
+}
+
+fn handler_args_attributes(variant_data: &syn::Variant) -> impl Iterator<Item = &syn::Attribute> {
+    variant_data
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident(HANDLER_ARGS_ATTRIBUTE))
+}
+
+fn handler_argument_names(
+    variant_data: &syn::Variant,
+    fields: &syn::FieldsUnnamed,
+) -> syn::Result<Vec<Ident>> {
+    let mut attrs = handler_args_attributes(variant_data);
+    let attr = match attrs.next() {
+        Some(attr) => attr,
+        None => {
+            return Ok((0..fields.unnamed.len())
+                .map(|index| Ident::new(&format!("arg{}", index), Span::call_site()))
+                .collect())
+        }
+    };
+
+    if let Some(duplicate) = attrs.next() {
+        return Err(syn::Error::new_spanned(
+            duplicate,
+            format!("{} is given more than once", HANDLER_ARGS_ATTRIBUTE),
+        ));
+    }
+
+    let names: Vec<Ident> = attr
+        .parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated)?
+        .into_iter()
+        .collect();
+
+    if names.len() != fields.unnamed.len() {
+        return Err(syn::Error::new_spanned(
+            attr,
+            format!(
+                "{} names {} arguments, but {} has {} fields",
+                HANDLER_ARGS_ATTRIBUTE,
+                names.len(),
+                variant_data.ident,
+                fields.unnamed.len()
+            ),
+        ));
+    }
+
+    for (index, name) in names.iter().enumerate() {
+        if names[..index].contains(name) {
+            return Err(syn::Error::new(
+                name.span(),
+                format!("argument {} is named more than once", name),
+            ));
+        }
+    }
+
+    Ok(names)
 }
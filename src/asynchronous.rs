@@ -0,0 +1,348 @@
+// Inbox and Outbox for tokio tasks. zmq hands out a descriptor that becomes
+// readable whenever the socket state may have changed, so we wait on that
+// and then ask the socket itself, instead of blocking a runtime thread.
+
+use crate::{
+    Address, Context, Envelope, Error, Headers, Inbox, Message, Outbox, Result, ShouldBlock,
+};
+use std::os::unix::io::RawFd;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+
+// zmq sockets cannot be shared between threads, the mutex is what lets
+// tasks holding a reference move between runtime threads. It is never held
+// across an await.
+pub struct AsyncInbox {
+    // Declared first so that the descriptor is deregistered before the
+    // socket owning it is closed
+    fd: AsyncFd<RawFd>,
+    inbox: Mutex<Inbox>,
+    address: Address,
+}
+
+impl AsyncInbox {
+    // Must be called from within a tokio runtime
+    pub fn new(inbox: Inbox) -> Self {
+        Self::try_new(inbox).expect("Cannot create async inbox")
+    }
+
+    pub fn try_new(inbox: Inbox) -> Result<Self> {
+        let fd = AsyncFd::new(inbox.control_socket.get_fd()?)?;
+
+        Ok(Self {
+            fd,
+            address: inbox.address().clone(),
+            inbox: Mutex::new(inbox),
+        })
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        lock(&self.inbox).stop_requested()
+    }
+
+    pub fn drain_requested(&self) -> bool {
+        lock(&self.inbox).drain_requested()
+    }
+
+    pub async fn receive_envelope(&self, should_block: ShouldBlock) -> Option<Envelope> {
+        self.try_receive_envelope(should_block)
+            .await
+            .expect("Actor failed to receive message")
+    }
+
+    pub async fn try_receive_envelope(
+        &self,
+        should_block: ShouldBlock,
+    ) -> Result<Option<Envelope>> {
+        loop {
+            {
+                let inbox = lock(&self.inbox);
+                let received = inbox.try_receive_envelope(ShouldBlock::from(false))?;
+                if received.is_some() || !should_block.0 || inbox.stop_requested() {
+                    return Ok(received);
+                }
+            }
+
+            wait_for_events(&self.fd).await?;
+        }
+    }
+
+    // Returns None if nothing arrives in time
+    pub async fn receive_envelope_timeout(&self, timeout: Duration) -> Option<Envelope> {
+        match self.try_receive_envelope_timeout(timeout).await {
+            Err(Error::Timeout) | Err(Error::Stopped) => None,
+            result => Some(result.expect("Actor failed to receive message")),
+        }
+    }
+
+    // Fails with Error::Timeout if nothing arrives in time
+    // and with Error::Stopped if a stop request does
+    pub async fn try_receive_envelope_timeout(&self, timeout: Duration) -> Result<Envelope> {
+        match tokio::time::timeout(timeout, self.try_receive_envelope(ShouldBlock::from(true)))
+            .await
+        {
+            Ok(received) => received?.ok_or(Error::Stopped),
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    pub fn context(&self, envelope: &Envelope) -> Context {
+        self.try_context(envelope)
+            .expect("Cannot read envelope context")
+    }
+
+    pub fn try_context(&self, envelope: &Envelope) -> Result<Context> {
+        Context::try_new(&lock(&self.inbox), envelope)
+    }
+}
+
+pub struct AsyncOutbox {
+    fd: AsyncFd<RawFd>,
+    outbox: Mutex<Outbox>,
+}
+
+impl AsyncOutbox {
+    // Must be called from within a tokio runtime
+    pub fn new(outbox: Outbox) -> Self {
+        Self::try_new(outbox).expect("Cannot create async outbox")
+    }
+
+    pub fn try_new(outbox: Outbox) -> Result<Self> {
+        let fd = AsyncFd::new(outbox.control_socket.get_fd()?)?;
+
+        Ok(Self {
+            fd,
+            outbox: Mutex::new(outbox),
+        })
+    }
+
+    pub async fn send_message<M: Message>(&self, message: &M) {
+        self.try_send_message(message)
+            .await
+            .expect("Cannot send message to worker");
+    }
+
+    pub async fn try_send_message<M: Message>(&self, message: &M) -> Result<()> {
+        self.try_send_message_with_headers(message, &Headers::new())
+            .await
+    }
+
+    pub async fn send_message_with_headers<M: Message>(&self, message: &M, headers: &Headers) {
+        self.try_send_message_with_headers(message, headers)
+            .await
+            .expect("Cannot send message to worker");
+    }
+
+    pub async fn try_send_message_with_headers<M: Message>(
+        &self,
+        message: &M,
+        headers: &Headers,
+    ) -> Result<()> {
+        let envelope = lock(&self.outbox).envelope_for(message, headers)?;
+        self.try_send_envelope(envelope).await
+    }
+
+    pub async fn send_envelope(&self, envelope: Envelope) {
+        self.try_send_envelope(envelope)
+            .await
+            .expect("Cannot send message to worker");
+    }
+
+    pub async fn try_send_envelope(&self, envelope: Envelope) -> Result<()> {
+        let (head, payload) = envelope.into_frames(&lock(&self.outbox).dest_address)?;
+
+        loop {
+            {
+                let outbox = lock(&self.outbox);
+                // zmq only refuses a message as a whole, once the first frame
+                // is queued the rest goes through as well
+                match outbox
+                    .control_socket
+                    .send(head.as_slice(), zmq::DONTWAIT | zmq::SNDMORE)
+                {
+                    Ok(()) => {
+                        outbox.control_socket.send(payload, zmq::DONTWAIT)?;
+                        return Ok(());
+                    }
+                    Err(zmq::Error::EAGAIN) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+
+            wait_for_events(&self.fd).await?;
+        }
+    }
+}
+
+// The descriptor only tells that something happened to the socket, callers
+// retry their operation afterwards and come back here if it would still block
+async fn wait_for_events(fd: &AsyncFd<RawFd>) -> Result<()> {
+    fd.readable().await?.clear_ready();
+    Ok(())
+}
+
+// A panic while holding the lock cannot leave the socket half-used, since
+// every operation under it is non-blocking and complete
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncInbox, AsyncOutbox};
+    use crate::{AddressType, Context, Inbox, Outbox, ShouldBlock, ShouldTerminate};
+    use custom_derive::actor_message;
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[actor_message(async, context)]
+    #[derive(Serialize, Deserialize)]
+    pub enum Counter {
+        Add(u64),
+        Report,
+    }
+
+    struct CountingWorker {
+        inbox: AsyncInbox,
+        total: u64,
+    }
+
+    impl CounterHandler for CountingWorker {
+        fn inbox(&self) -> &AsyncInbox {
+            &self.inbox
+        }
+
+        async fn handle_add(&mut self, _ctx: &Context, arg0: u64) -> ShouldTerminate {
+            // Gives the other actors a chance to run in between
+            tokio::task::yield_now().await;
+            self.total += arg0;
+            ShouldTerminate::from(false)
+        }
+
+        async fn handle_report(&mut self, ctx: &Context) -> ShouldTerminate {
+            let outbox = AsyncOutbox::new(ctx.reply_outbox());
+            outbox.send_message(&Counter::Add(self.total)).await;
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn many_actors_on_few_threads() {
+        const ACTOR_COUNT: u64 = 32;
+
+        let ctx = zmq::Context::new();
+        let collector = AsyncInbox::new(Inbox::bind_new(ctx.clone(), AddressType::Local));
+
+        let mut actors = Vec::new();
+        for i in 0..ACTOR_COUNT {
+            let mut worker = CountingWorker {
+                inbox: AsyncInbox::new(Inbox::bind_new(ctx.clone(), AddressType::Local)),
+                total: 0,
+            };
+            let outbox = AsyncOutbox::new(Outbox::new(
+                ctx.clone(),
+                worker.inbox.address(),
+                collector.address(),
+            ));
+            actors.push(tokio::spawn(async move { worker.run().await }));
+
+            for _ in 0..=i {
+                outbox.send_message(&Counter::Add(1)).await;
+            }
+            outbox.send_message(&Counter::Report).await;
+        }
+
+        let mut totals = Vec::new();
+        for _ in 0..ACTOR_COUNT {
+            let envelope = collector
+                .receive_envelope_timeout(Duration::from_secs(10))
+                .await
+                .expect("Actor did not report");
+            match envelope.decode() {
+                Counter::Add(total) => totals.push(total),
+                Counter::Report => panic!("Expected a total"),
+            }
+        }
+        for actor in actors {
+            actor.await.unwrap();
+        }
+
+        totals.sort_unstable();
+        assert_eq!(totals, (1..=ACTOR_COUNT).collect::<Vec<_>>());
+    }
+
+    #[actor_message(async)]
+    #[derive(Serialize, Deserialize)]
+    pub enum Quit {
+        Now,
+    }
+
+    struct StrictWorker {
+        inbox: AsyncInbox,
+        dead_letters: AsyncOutbox,
+    }
+
+    impl QuitHandler for StrictWorker {
+        fn inbox(&self) -> &AsyncInbox {
+            &self.inbox
+        }
+
+        fn dead_letters(&self) -> Option<&AsyncOutbox> {
+            Some(&self.dead_letters)
+        }
+
+        async fn handle_now(&mut self) -> ShouldTerminate {
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn route_other_protocols_to_dead_letters() {
+        let ctx = zmq::Context::new();
+        let sink = AsyncInbox::new(Inbox::bind_new(ctx.clone(), AddressType::Local));
+
+        let mut worker = StrictWorker {
+            inbox: AsyncInbox::new(Inbox::bind_new(ctx.clone(), AddressType::Local)),
+            dead_letters: AsyncOutbox::new(Outbox::new(
+                ctx.clone(),
+                sink.address(),
+                sink.address(),
+            )),
+        };
+        let outbox = AsyncOutbox::new(Outbox::new(ctx, worker.inbox.address(), sink.address()));
+
+        outbox.send_message(&Counter::Add(7)).await;
+        outbox.send_message(&Quit::Now).await;
+        worker.run().await;
+
+        let envelope = sink
+            .receive_envelope_timeout(Duration::from_secs(10))
+            .await
+            .expect("Dead letter did not arrive");
+        assert!(matches!(envelope.decode(), Counter::Add(7)));
+    }
+
+    #[tokio::test]
+    async fn receive_timeout() {
+        let ctx = zmq::Context::new();
+        let inbox = AsyncInbox::new(Inbox::bind_new(ctx, AddressType::Local));
+
+        assert!(inbox
+            .receive_envelope(ShouldBlock::from(false))
+            .await
+            .is_none());
+        assert!(matches!(
+            inbox
+                .try_receive_envelope_timeout(Duration::from_millis(10))
+                .await,
+            Err(crate::Error::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+// How messages are turned into payload bytes. The sender picks the codec with
+// Outbox::with_codec and records its id in the envelope, receivers decode with
+// whatever the envelope says.
+
+use crate::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+mod private {
+    pub trait Sealed {}
+}
+
+// Sealed, since receivers only know the codecs of this crate by their ids
+pub trait Codec: private::Sealed {
+    // Written to the envelope, see WIRE_FORMAT.md for the ids in use
+    const ID: u8;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>>;
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M>;
+}
+
+// The default, compact but only readable by bincode itself
+pub struct Bincode;
+
+impl private::Sealed for Bincode {}
+
+impl Codec for Bincode {
+    const ID: u8 = 0;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(message)?)
+    }
+
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl private::Sealed for Json {}
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const ID: u8 = 1;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>> {
+        serde_json::to_vec(message).map_err(|err| Error::Codec(err.into()))
+    }
+
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M> {
+        serde_json::from_slice(bytes).map_err(|err| Error::Codec(err.into()))
+    }
+}
+
+// Structs are written as maps, so that other languages get the field names
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl private::Sealed for MessagePack {}
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const ID: u8 = 2;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(message).map_err(|err| Error::Codec(err.into()))
+    }
+
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M> {
+        rmp_serde::from_slice(bytes).map_err(|err| Error::Codec(err.into()))
+    }
+}
+
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl private::Sealed for Cbor {}
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const ID: u8 = 3;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>> {
+        serde_cbor::to_vec(message).map_err(|err| Error::Codec(err.into()))
+    }
+
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M> {
+        serde_cbor::from_slice(bytes).map_err(|err| Error::Codec(err.into()))
+    }
+}
+
+// Codecs left out at build time fail with Error::UnsupportedCodec
+pub(crate) fn encode<M: Serialize>(codec_id: u8, message: &M) -> Result<Vec<u8>> {
+    match codec_id {
+        Bincode::ID => Bincode::encode(message),
+        #[cfg(feature = "json")]
+        Json::ID => Json::encode(message),
+        #[cfg(feature = "msgpack")]
+        MessagePack::ID => MessagePack::encode(message),
+        #[cfg(feature = "cbor")]
+        Cbor::ID => Cbor::encode(message),
+        _ => Err(Error::UnsupportedCodec(codec_id)),
+    }
+}
+
+pub(crate) fn decode<M: DeserializeOwned>(codec_id: u8, bytes: &[u8]) -> Result<M> {
+    match codec_id {
+        Bincode::ID => Bincode::decode(bytes),
+        #[cfg(feature = "json")]
+        Json::ID => Json::decode(bytes),
+        #[cfg(feature = "msgpack")]
+        MessagePack::ID => MessagePack::decode(bytes),
+        #[cfg(feature = "cbor")]
+        Cbor::ID => Cbor::decode(bytes),
+        _ => Err(Error::UnsupportedCodec(codec_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Bincode, Codec};
+    use crate::Error;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Sample {
+        Unit,
+        Pair(u8, String),
+        Named { id: u64, tags: Vec<String> },
+    }
+
+    fn samples() -> Vec<Sample> {
+        vec![
+            Sample::Unit,
+            Sample::Pair(7, "seven".to_owned()),
+            Sample::Named {
+                id: u64::MAX,
+                tags: vec!["a".to_owned(), "b".to_owned()],
+            },
+        ]
+    }
+
+    fn round_trip<C: Codec>() {
+        for sample in samples() {
+            let bytes = encode(C::ID, &sample).unwrap();
+            assert_eq!(decode::<Sample>(C::ID, &bytes).unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        round_trip::<Bincode>();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        round_trip::<super::Json>();
+
+        let bytes = encode(super::Json::ID, &Sample::Pair(1, "one".to_owned())).unwrap();
+        assert_eq!(bytes, br#"{"Pair":[1,"one"]}"#);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        round_trip::<super::MessagePack>();
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        round_trip::<super::Cbor>();
+    }
+
+    #[test]
+    fn unknown_codec_is_an_error() {
+        assert!(matches!(
+            encode(0x7f, &Sample::Unit),
+            Err(Error::UnsupportedCodec(0x7f))
+        ));
+        assert!(matches!(
+            decode::<Sample>(0x7f, &[]),
+            Err(Error::UnsupportedCodec(0x7f))
+        ));
+    }
+}
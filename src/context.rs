@@ -0,0 +1,94 @@
+// What a handler knows about the message it is handling, passed to handlers
+// generated by #[actor_message(context)].
+
+use crate::{
+    message_envelope, Address, DestAddress, Envelope, Headers, Inbox, Message, Outbox, Result,
+    SourceAddress,
+};
+
+pub struct Context {
+    zmq_ctx: zmq::Context,
+    // Replies are sent from here, so that the sender can answer them in turn
+    inbox_address: Address,
+    source_address: SourceAddress,
+    dest_address: DestAddress,
+    headers: Headers,
+    correlation_id: Option<u64>,
+    // Replies are encoded the way the message was
+    codec_id: u8,
+}
+
+impl Context {
+    pub fn new(inbox: &Inbox, envelope: &Envelope) -> Self {
+        Self::try_new(inbox, envelope).expect("Cannot read envelope context")
+    }
+
+    pub fn try_new(inbox: &Inbox, envelope: &Envelope) -> Result<Self> {
+        let (dest_address, source_address) = envelope.try_peek()?;
+
+        Ok(Self {
+            zmq_ctx: inbox.zmq_ctx.clone(),
+            inbox_address: inbox.address().clone(),
+            source_address,
+            dest_address,
+            headers: envelope.try_headers()?,
+            correlation_id: envelope.correlation_id(),
+            codec_id: envelope.codec_id(),
+        })
+    }
+
+    pub fn source_address(&self) -> &SourceAddress {
+        &self.source_address
+    }
+
+    pub fn dest_address(&self) -> &DestAddress {
+        &self.dest_address
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key).map(String::as_str)
+    }
+
+    pub fn correlation_id(&self) -> Option<u64> {
+        self.correlation_id
+    }
+
+    // Connects a new socket on every call, keep the outbox around when
+    // talking back to the sender more than once. Uses the codec of the message.
+    pub fn reply_outbox(&self) -> Outbox {
+        self.try_reply_outbox().expect("Cannot create reply outbox")
+    }
+
+    pub fn try_reply_outbox(&self) -> Result<Outbox> {
+        Ok(Outbox::try_new(
+            self.zmq_ctx.clone(),
+            &self.source_address.0,
+            &self.inbox_address,
+        )?
+        .with_codec_id(self.codec_id))
+    }
+
+    // Answers the message, carrying over its correlation id so that
+    // Outbox::ask can match the two up
+    pub fn reply<R: Message>(&self, response: &R) {
+        self.try_reply(response).expect("Cannot send reply");
+    }
+
+    pub fn try_reply<R: Message>(&self, response: &R) -> Result<()> {
+        let mut reply = message_envelope(
+            response,
+            self.codec_id,
+            &self.source_address.0,
+            &self.inbox_address,
+        )?;
+        if let Some(correlation_id) = self.correlation_id {
+            reply = reply.try_with_correlation_id(correlation_id)?;
+        }
+
+        self.try_reply_outbox()?.try_send_envelope(reply)
+    }
+}
@@ -0,0 +1,841 @@
+// Envelope framing. The byte layout is described in WIRE_FORMAT.md,
+// keep the two in sync when touching anything here.
+
+use crate::codec::{self, Bincode, Codec};
+use crate::{Address, DestAddress, Error, Message, Result, SourceAddress};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+
+pub const MAGIC: [u8; 4] = [0xff, b'Y', b'A', b'E'];
+pub const FORMAT_VERSION: u8 = 1;
+
+// magic, version, flags, header length
+const FIXED_HEADER_LENGTH: usize = 4 + 1 + 1 + 4;
+
+// Header flags
+// Asks the receiving actor to leave its run loop, the payload is empty
+const FLAG_STOP_REQUEST: u8 = 0x01;
+// Set along with FLAG_STOP_REQUEST, the actor handles what is already queued first
+const FLAG_DRAIN: u8 = 0x02;
+
+// Extension kinds, each extension is [kind: u8][body length: u32][body]
+const EXTENSION_HEADER: u8 = 1;
+const EXTENSION_CORRELATION_ID: u8 = 2;
+const EXTENSION_TYPE_TAG: u8 = 3;
+const EXTENSION_CODEC: u8 = 4;
+const EXTENSION_STOP_GENERATION: u8 = 5;
+
+pub type Headers = BTreeMap<String, String>;
+
+// Before the header existed, envelopes ended with
+//
+//   [source][destination][source length: u16][destination length: u16][TRAILER_MAGIC]
+//
+// and before that with two zero-padded addresses of LEGACY_ADDRESS_LENGTH bytes
+// (source, then destination). We still accept both when opening.
+// 0xff never occurs in utf-8, so a legacy address cannot end with the magic by accident.
+const TRAILER_MAGIC: [u8; 4] = [0xff, b'y', b'a', 0x01];
+const TRAILER_LENGTH: usize = 2 + 2 + TRAILER_MAGIC.len();
+const LEGACY_ADDRESS_LENGTH: usize = 32;
+
+// Envelopes we send are two zmq frames, the header and the payload, so that the
+// payload buffer can be handed to zmq as is and forwarded without being copied.
+// Envelopes built from a single Vec (or received from older peers) keep
+// everything in `head` and are split on demand.
+#[derive(Debug)]
+pub struct Envelope {
+    head: Vec<u8>,
+    payload: Option<zmq::Message>,
+}
+
+struct Parsed {
+    dest_address: Address,
+    source_address: Address,
+    flags: u8,
+    // Both ranges index into `head`
+    extensions: Range<usize>,
+    payload: Range<usize>,
+}
+
+impl Envelope {
+    pub fn new(message_bytes: Vec<u8>, dest_address: &Address, source_address: &Address) -> Self {
+        Self {
+            head: encode_header(dest_address, source_address, 0, &[]),
+            payload: Some(zmq::Message::from(message_bytes)),
+        }
+    }
+
+    // Sent by ActorSystem::shutdown, Inbox swallows it instead of handing it out
+    pub fn stop_request(dest_address: &Address, source_address: &Address) -> Self {
+        Self {
+            head: encode_header(dest_address, source_address, FLAG_STOP_REQUEST, &[]),
+            payload: Some(zmq::Message::new()),
+        }
+    }
+
+    // A stop request after which the actor still handles the messages that
+    // were queued in its inbox when the request arrived
+    pub fn drain_request(dest_address: &Address, source_address: &Address) -> Self {
+        Self {
+            head: encode_header(
+                dest_address,
+                source_address,
+                FLAG_STOP_REQUEST | FLAG_DRAIN,
+                &[],
+            ),
+            payload: Some(zmq::Message::new()),
+        }
+    }
+
+    // Also true for drain requests
+    pub fn is_stop_request(&self) -> bool {
+        self.flags() & FLAG_STOP_REQUEST != 0
+    }
+
+    pub fn is_drain_request(&self) -> bool {
+        self.is_stop_request() && self.flags() & FLAG_DRAIN != 0
+    }
+
+    fn flags(&self) -> u8 {
+        self.parse().map(|parsed| parsed.flags).unwrap_or(0)
+    }
+
+    pub(crate) fn from_frames(head: Vec<u8>, payload: zmq::Message) -> Self {
+        Self {
+            head,
+            payload: Some(payload),
+        }
+    }
+
+    pub fn open(self) -> (DestAddress, SourceAddress, Vec<u8>) {
+        self.try_open().expect("Cannot open envelope")
+    }
+
+    pub fn try_open(self) -> Result<(DestAddress, SourceAddress, Vec<u8>)> {
+        let parsed = self.parse()?;
+        let message_bytes = match self.payload {
+            Some(payload) => payload.to_vec(),
+            None => {
+                let mut head = self.head;
+                head.truncate(parsed.payload.end);
+                head.drain(..parsed.payload.start);
+                head
+            }
+        };
+
+        Ok((
+            parsed.dest_address.into(),
+            parsed.source_address.into(),
+            message_bytes,
+        ))
+    }
+
+    pub fn peek(&self) -> (DestAddress, SourceAddress) {
+        self.try_peek().expect("Cannot peek into envelope")
+    }
+
+    pub fn try_peek(&self) -> Result<(DestAddress, SourceAddress)> {
+        let parsed = self.parse()?;
+
+        Ok((parsed.dest_address.into(), parsed.source_address.into()))
+    }
+
+    // Borrows the payload straight from the zmq buffer
+    pub fn payload(&self) -> Result<&[u8]> {
+        match &self.payload {
+            Some(payload) => {
+                self.parse()?;
+                Ok(payload)
+            }
+            None => Ok(&self.head[self.parse()?.payload]),
+        }
+    }
+
+    // Deserializes the message straight from the payload, without opening the
+    // envelope, with whatever codec the sender used
+    pub fn decode<M: DeserializeOwned>(&self) -> M {
+        self.try_decode().expect("Cannot deserialize envelope")
+    }
+
+    pub fn try_decode<M: DeserializeOwned>(&self) -> Result<M> {
+        codec::decode(self.codec_id(), self.payload()?)
+    }
+
+    // Single-frame encoding, the same bytes Inbox::receive hands out
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.head;
+        if let Some(payload) = self.payload {
+            bytes.extend_from_slice(&payload);
+        }
+
+        bytes
+    }
+
+    pub fn with_header(self, key: &str, value: &str) -> Self {
+        self.try_with_header(key, value)
+            .expect("Cannot add header to envelope")
+    }
+
+    // Setting a header that is already there replaces its value
+    pub fn try_with_header(self, key: &str, value: &str) -> Result<Self> {
+        let key_length = u16::try_from(key.len()).map_err(|_| {
+            Error::MalformedEnvelope(format!("header key is longer than {} bytes", u16::MAX))
+        })?;
+
+        let mut body = Vec::with_capacity(2 + key.len() + value.len());
+        body.extend_from_slice(&key_length.to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.extend_from_slice(value.as_bytes());
+
+        self.with_extension(EXTENSION_HEADER, &body, |existing| {
+            decode_header_entry(existing)
+                .map(|(existing_key, _)| existing_key == key)
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        let parsed = self.parse().ok()?;
+
+        self.extensions(&parsed)
+            .filter(|&(kind, _)| kind == EXTENSION_HEADER)
+            .filter_map(|(_, body)| decode_header_entry(body).ok())
+            .find(|&(existing_key, _)| existing_key == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn headers(&self) -> Headers {
+        self.try_headers().expect("Cannot read envelope headers")
+    }
+
+    pub fn try_headers(&self) -> Result<Headers> {
+        let parsed = self.parse()?;
+
+        self.extensions(&parsed)
+            .filter(|&(kind, _)| kind == EXTENSION_HEADER)
+            .map(|(_, body)| {
+                decode_header_entry(body).map(|(key, value)| (key.to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    pub fn with_correlation_id(self, correlation_id: u64) -> Self {
+        self.try_with_correlation_id(correlation_id)
+            .expect("Cannot add correlation id to envelope")
+    }
+
+    pub fn try_with_correlation_id(self, correlation_id: u64) -> Result<Self> {
+        self.with_extension(
+            EXTENSION_CORRELATION_ID,
+            &correlation_id.to_le_bytes(),
+            |_| true,
+        )
+    }
+
+    // Set by Outbox::ask and copied into the reply by Inbox::reply
+    pub fn correlation_id(&self) -> Option<u64> {
+        self.u64_extension(EXTENSION_CORRELATION_ID)
+    }
+
+    pub fn with_type_tag(self, type_tag: u64) -> Self {
+        self.try_with_type_tag(type_tag)
+            .expect("Cannot add type tag to envelope")
+    }
+
+    pub fn try_with_type_tag(self, type_tag: u64) -> Result<Self> {
+        self.with_extension(EXTENSION_TYPE_TAG, &type_tag.to_le_bytes(), |_| true)
+    }
+
+    // Identifies the message type of the payload, see Message::type_tag
+    pub fn type_tag(&self) -> Option<u64> {
+        self.u64_extension(EXTENSION_TYPE_TAG)
+    }
+
+    // Restricts a stop request to the inbox bound for that generation of a
+    // supervised child, see Inbox::with_generation
+    pub(crate) fn try_with_stop_generation(self, generation: u64) -> Result<Self> {
+        self.with_extension(EXTENSION_STOP_GENERATION, &generation.to_le_bytes(), |_| {
+            true
+        })
+    }
+
+    pub(crate) fn stop_generation(&self) -> Option<u64> {
+        self.u64_extension(EXTENSION_STOP_GENERATION)
+    }
+
+    // Fails with Error::ProtocolMismatch if the payload was tagged as another
+    // message type than M. Untagged envelopes and untagged types always pass,
+    // bincode alone cannot tell them apart.
+    pub fn try_check_type_tag<M: Message>(&self) -> Result<()> {
+        match (M::type_tag(), self.type_tag()) {
+            (Some(expected), Some(found)) if expected != found => {
+                Err(Error::ProtocolMismatch { expected, found })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Rewrites the header for a new destination, keeping the source so that replies
+    // still reach the original sender. The payload frame is passed through untouched.
+    pub(crate) fn into_frames(self, dest_address: &Address) -> Result<(Vec<u8>, zmq::Message)> {
+        let parsed = self.parse()?;
+        let head = encode_header(
+            dest_address,
+            &parsed.source_address,
+            parsed.flags,
+            &self.head[parsed.extensions.clone()],
+        );
+
+        Ok((head, self.into_payload(&parsed)))
+    }
+
+    // Adds an extension, dropping the ones `replaces` matches
+    fn with_extension(
+        self,
+        kind: u8,
+        body: &[u8],
+        replaces: impl Fn(&[u8]) -> bool,
+    ) -> Result<Self> {
+        let parsed = self.parse()?;
+
+        let mut extensions = Vec::with_capacity(parsed.extensions.len() + 5 + body.len());
+        for (existing_kind, existing_body) in self.extensions(&parsed) {
+            if existing_kind != kind || !replaces(existing_body) {
+                encode_extension(&mut extensions, existing_kind, existing_body);
+            }
+        }
+        encode_extension(&mut extensions, kind, body);
+
+        let head = encode_header(
+            &parsed.dest_address,
+            &parsed.source_address,
+            parsed.flags,
+            &extensions,
+        );
+
+        Ok(Self {
+            head,
+            payload: Some(self.into_payload(&parsed)),
+        })
+    }
+
+    pub fn with_codec_id(self, codec_id: u8) -> Self {
+        self.try_with_codec_id(codec_id)
+            .expect("Cannot add codec id to envelope")
+    }
+
+    pub fn try_with_codec_id(self, codec_id: u8) -> Result<Self> {
+        self.with_extension(EXTENSION_CODEC, &[codec_id], |_| true)
+    }
+
+    // Which Codec the payload is encoded with, envelopes that do not say are bincode
+    pub fn codec_id(&self) -> u8 {
+        let parsed = match self.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => return Bincode::ID,
+        };
+
+        self.extensions(&parsed)
+            .filter(|&(kind, _)| kind == EXTENSION_CODEC)
+            .find_map(|(_, body)| body.first().copied())
+            .unwrap_or(Bincode::ID)
+    }
+
+    fn u64_extension(&self, kind: u8) -> Option<u64> {
+        let parsed = self.parse().ok()?;
+
+        self.extensions(&parsed)
+            .filter(|&(existing_kind, _)| existing_kind == kind)
+            .find_map(|(_, body)| {
+                let mut reader = Reader::new(body);
+                reader.read_u64().ok()
+            })
+    }
+
+    // Extensions are validated by parse(), so this does not need to care about truncation
+    fn extensions<'a>(&'a self, parsed: &Parsed) -> impl Iterator<Item = (u8, &'a [u8])> + 'a {
+        let mut reader = Reader::new(&self.head[parsed.extensions.clone()]);
+        std::iter::from_fn(move || reader.read_extension().ok().flatten())
+    }
+
+    // Splits the payload out of single-frame envelopes, which costs a copy
+    fn into_payload(self, parsed: &Parsed) -> zmq::Message {
+        match self.payload {
+            Some(payload) => payload,
+            None => zmq::Message::from(&self.head[parsed.payload.clone()]),
+        }
+    }
+
+    fn parse(&self) -> Result<Parsed> {
+        if self.head.starts_with(&MAGIC) {
+            self.parse_header()
+        } else if self.payload.is_some() {
+            Err(Error::MalformedEnvelope(
+                "header frame does not start with the magic".to_owned(),
+            ))
+        } else if self.head.ends_with(&TRAILER_MAGIC) {
+            self.parse_trailer()
+        } else {
+            self.parse_legacy_trailer()
+        }
+    }
+
+    fn parse_header(&self) -> Result<Parsed> {
+        let mut reader = Reader::new(&self.head);
+        reader.take(MAGIC.len())?;
+
+        let version = reader.read_u8()?;
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        // Stop (0x01) and drain (0x02) requests, unknown flags are ignored by design
+        let flags = reader.read_u8()?;
+
+        let header_length = reader.read_u32()? as usize;
+        if header_length > self.head.len()
+            || (self.payload.is_some() && header_length != self.head.len())
+        {
+            return Err(Error::MalformedEnvelope(format!(
+                "header claims {} bytes, but the frame has {}",
+                header_length,
+                self.head.len()
+            )));
+        }
+
+        let mut reader = Reader::new(&self.head[..header_length]);
+        reader.take(FIXED_HEADER_LENGTH)?;
+
+        let source_length = reader.read_u16()? as usize;
+        let source_address = read_envelope_address(reader.take(source_length)?)?;
+        let dest_length = reader.read_u16()? as usize;
+        let dest_address = read_envelope_address(reader.take(dest_length)?)?;
+
+        // Whatever is left up to header_length is a list of extensions.
+        // We skip kinds we do not know, but they must still be well-formed.
+        let extensions = reader.position..header_length;
+        while reader.read_extension()?.is_some() {}
+
+        Ok(Parsed {
+            dest_address,
+            source_address,
+            flags,
+            extensions,
+            payload: header_length..self.head.len(),
+        })
+    }
+
+    fn parse_trailer(&self) -> Result<Parsed> {
+        let truncated = || Error::MalformedEnvelope("trailer is truncated".to_owned());
+
+        let lengths_start = self
+            .head
+            .len()
+            .checked_sub(TRAILER_LENGTH)
+            .ok_or_else(truncated)?;
+        let read_length = |offset: usize| {
+            u16::from_le_bytes([
+                self.head[lengths_start + offset],
+                self.head[lengths_start + offset + 1],
+            ]) as usize
+        };
+
+        let dest_start = lengths_start
+            .checked_sub(read_length(2))
+            .ok_or_else(truncated)?;
+        let source_start = dest_start
+            .checked_sub(read_length(0))
+            .ok_or_else(truncated)?;
+
+        Ok(Parsed {
+            dest_address: read_envelope_address(&self.head[dest_start..lengths_start])?,
+            source_address: read_envelope_address(&self.head[source_start..dest_start])?,
+            flags: 0,
+            extensions: 0..0,
+            payload: 0..source_start,
+        })
+    }
+
+    fn parse_legacy_trailer(&self) -> Result<Parsed> {
+        let source_start = self
+            .head
+            .len()
+            .checked_sub(LEGACY_ADDRESS_LENGTH * 2)
+            .ok_or_else(|| {
+                Error::MalformedEnvelope(format!(
+                    "expected at least {} bytes, got {}",
+                    LEGACY_ADDRESS_LENGTH * 2,
+                    self.head.len()
+                ))
+            })?;
+        let dest_start = source_start + LEGACY_ADDRESS_LENGTH;
+
+        Ok(Parsed {
+            dest_address: read_envelope_address(strip_zero_padding(&self.head[dest_start..]))?,
+            source_address: read_envelope_address(strip_zero_padding(
+                &self.head[source_start..dest_start],
+            ))?,
+            flags: 0,
+            extensions: 0..0,
+            payload: 0..source_start,
+        })
+    }
+}
+
+impl From<Vec<u8>> for Envelope {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self {
+            head: bytes,
+            payload: None,
+        }
+    }
+}
+
+fn encode_header(
+    dest_address: &Address,
+    source_address: &Address,
+    flags: u8,
+    extensions: &[u8],
+) -> Vec<u8> {
+    let source_bytes = source_address.as_str().as_bytes();
+    let dest_bytes = dest_address.as_str().as_bytes();
+    // Addresses are validated on construction, so these only fire on a bug
+    let source_length = u16::try_from(source_bytes.len()).expect("Source address is too long");
+    let dest_length = u16::try_from(dest_bytes.len()).expect("Destination address is too long");
+
+    let header_length =
+        FIXED_HEADER_LENGTH + 2 + source_bytes.len() + 2 + dest_bytes.len() + extensions.len();
+
+    let mut bytes = Vec::with_capacity(header_length);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(FORMAT_VERSION);
+    bytes.push(flags);
+    bytes.extend_from_slice(&(header_length as u32).to_le_bytes());
+    bytes.extend_from_slice(&source_length.to_le_bytes());
+    bytes.extend_from_slice(source_bytes);
+    bytes.extend_from_slice(&dest_length.to_le_bytes());
+    bytes.extend_from_slice(dest_bytes);
+    bytes.extend_from_slice(extensions);
+
+    bytes
+}
+
+fn encode_extension(bytes: &mut Vec<u8>, kind: u8, body: &[u8]) {
+    bytes.push(kind);
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(body);
+}
+
+fn decode_header_entry(body: &[u8]) -> Result<(&str, &str)> {
+    let mut reader = Reader::new(body);
+    let key_length = reader.read_u16()? as usize;
+    let key = reader.take(key_length)?;
+    let value = &body[reader.position..];
+
+    match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+        (Ok(key), Ok(value)) => Ok((key, value)),
+        _ => Err(Error::MalformedEnvelope(
+            "header is not valid utf-8".to_owned(),
+        )),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| Error::MalformedEnvelope("header is truncated".to_owned()))?;
+
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // Returns kind and body, or None once everything has been read
+    fn read_extension(&mut self) -> Result<Option<(u8, &'a [u8])>> {
+        if self.position == self.bytes.len() {
+            return Ok(None);
+        }
+
+        let kind = self.read_u8()?;
+        let body_length = self.read_u32()? as usize;
+        Ok(Some((kind, self.take(body_length)?)))
+    }
+}
+
+// Legacy addresses are padded with zero bytes up to LEGACY_ADDRESS_LENGTH
+fn strip_zero_padding(bytes: &[u8]) -> &[u8] {
+    let zero_byte_position = bytes.iter().position(|&v| v == 0).unwrap_or(bytes.len());
+    &bytes[..zero_byte_position]
+}
+
+fn read_envelope_address(bytes: &[u8]) -> Result<Address> {
+    std::str::from_utf8(bytes)
+        .map_err(|_| Error::AddressParse("connection string is not valid utf-8".to_owned()))
+        .and_then(Address::try_from_conn_string)
+        .map_err(|err| Error::MalformedEnvelope(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Envelope, FLAG_DRAIN, FLAG_STOP_REQUEST, FORMAT_VERSION, MAGIC};
+    use crate::{Address, Error};
+
+    fn addresses() -> (Address, Address) {
+        (
+            "inproc://dest".parse().unwrap(),
+            "tcp://127.0.0.1:5555".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn header_layout() {
+        let (dest_address, source_address) = addresses();
+        let envelope = Envelope::new(vec![7, 8], &dest_address, &source_address);
+
+        let mut expected = MAGIC.to_vec();
+        expected.push(FORMAT_VERSION);
+        expected.push(0);
+        expected.extend_from_slice(&47u32.to_le_bytes());
+        expected.extend_from_slice(&20u16.to_le_bytes());
+        expected.extend_from_slice(b"tcp://127.0.0.1:5555");
+        expected.extend_from_slice(&13u16.to_le_bytes());
+        expected.extend_from_slice(b"inproc://dest");
+        expected.extend_from_slice(&[7, 8]);
+
+        assert_eq!(envelope.into_bytes(), expected);
+    }
+
+    #[test]
+    fn skip_unknown_header_extensions() {
+        let (dest_address, source_address) = addresses();
+        let mut bytes = Envelope::new(vec![], &dest_address, &source_address).into_bytes();
+
+        // Pretend a newer peer added an extension we do not understand
+        bytes.extend_from_slice(&[0x7f, 3, 0, 0, 0, 0xaa, 0xbb, 0xcc]);
+        let header_length = bytes.len() as u32;
+        bytes[6..10].copy_from_slice(&header_length.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let (dest, source, message_bytes) = Envelope::from(bytes).open();
+        assert_eq!(&dest, &dest_address);
+        assert_eq!(&source, &source_address);
+        assert_eq!(message_bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let (dest_address, source_address) = addresses();
+        let mut bytes = Envelope::new(vec![1], &dest_address, &source_address).into_bytes();
+        bytes[4] = FORMAT_VERSION + 1;
+
+        assert!(matches!(
+            Envelope::from(bytes).try_open(),
+            Err(Error::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn reject_truncated_header() {
+        let (dest_address, source_address) = addresses();
+        let bytes = Envelope::new(vec![], &dest_address, &source_address).into_bytes();
+
+        for length in MAGIC.len()..bytes.len() {
+            assert!(matches!(
+                Envelope::from(bytes[..length].to_vec()).try_peek(),
+                Err(Error::MalformedEnvelope(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn open_trailer_envelope() {
+        let (dest_address, source_address) = addresses();
+
+        let mut bytes = vec![4, 5, 6];
+        bytes.extend_from_slice(source_address.as_str().as_bytes());
+        bytes.extend_from_slice(dest_address.as_str().as_bytes());
+        bytes.extend_from_slice(&(source_address.as_str().len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(dest_address.as_str().len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&super::TRAILER_MAGIC);
+
+        let (dest, source, message_bytes) = Envelope::from(bytes).open();
+        assert_eq!(&dest, &dest_address);
+        assert_eq!(&source, &source_address);
+        assert_eq!(message_bytes, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn headers() {
+        let (dest_address, source_address) = addresses();
+        let envelope = Envelope::new(vec![1, 2, 3], &dest_address, &source_address)
+            .with_header("tenant", "acme")
+            .with_header("content-type", "application/bincode")
+            .with_header("tenant", "globex");
+
+        assert_eq!(envelope.header("tenant"), Some("globex"));
+        assert_eq!(envelope.header("content-type"), Some("application/bincode"));
+        assert_eq!(envelope.header("deadline"), None);
+        assert_eq!(envelope.headers().len(), 2);
+
+        // Headers survive the single-frame encoding as well
+        let envelope = Envelope::from(envelope.into_bytes());
+        assert_eq!(envelope.header("tenant"), Some("globex"));
+
+        let (dest, source, message_bytes) = envelope.open();
+        assert_eq!(&dest, &dest_address);
+        assert_eq!(&source, &source_address);
+        assert_eq!(message_bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn correlation_id() {
+        let (dest_address, source_address) = addresses();
+        let envelope =
+            Envelope::new(vec![1], &dest_address, &source_address).with_header("tenant", "acme");
+        assert_eq!(envelope.correlation_id(), None);
+
+        let envelope = envelope
+            .with_correlation_id(7)
+            .with_correlation_id(u64::MAX);
+        assert_eq!(envelope.correlation_id(), Some(u64::MAX));
+        assert_eq!(envelope.header("tenant"), Some("acme"));
+
+        let envelope = Envelope::from(envelope.into_bytes());
+        assert_eq!(envelope.correlation_id(), Some(u64::MAX));
+        assert_eq!(envelope.headers().len(), 1);
+    }
+
+    #[test]
+    fn type_tag() {
+        #[derive(serde::Serialize)]
+        struct Tagged;
+        impl crate::Message for Tagged {
+            const TYPE_TAG: Option<u64> = Some(7);
+        }
+
+        #[derive(serde::Serialize)]
+        struct Untagged;
+        impl crate::Message for Untagged {}
+
+        let (dest_address, source_address) = addresses();
+        let envelope = Envelope::new(vec![1], &dest_address, &source_address)
+            .with_correlation_id(1)
+            .with_type_tag(7);
+        assert_eq!(envelope.type_tag(), Some(7));
+        assert_eq!(envelope.correlation_id(), Some(1));
+        assert!(envelope.try_check_type_tag::<Tagged>().is_ok());
+        assert!(envelope.try_check_type_tag::<Untagged>().is_ok());
+
+        let envelope = Envelope::from(envelope.with_type_tag(8).into_bytes());
+        assert!(matches!(
+            envelope.try_check_type_tag::<Tagged>(),
+            Err(Error::ProtocolMismatch {
+                expected: 7,
+                found: 8
+            })
+        ));
+
+        let envelope = Envelope::new(vec![1], &dest_address, &source_address);
+        assert_eq!(envelope.type_tag(), None);
+        assert!(envelope.try_check_type_tag::<Tagged>().is_ok());
+    }
+
+    #[test]
+    fn stop_request() {
+        let (dest_address, source_address) = addresses();
+        assert!(!Envelope::new(vec![], &dest_address, &source_address).is_stop_request());
+
+        let envelope = Envelope::stop_request(&dest_address, &source_address);
+        assert!(envelope.is_stop_request());
+        // Forwarding keeps the flags
+        let (head, _) = envelope.into_frames(&source_address).unwrap();
+        assert_eq!(head[5], FLAG_STOP_REQUEST);
+        assert!(Envelope::from(head).is_stop_request());
+    }
+
+    #[test]
+    fn drain_request() {
+        let (dest_address, source_address) = addresses();
+        assert!(!Envelope::stop_request(&dest_address, &source_address).is_drain_request());
+
+        let envelope = Envelope::drain_request(&dest_address, &source_address);
+        assert!(envelope.is_stop_request());
+        assert!(envelope.is_drain_request());
+        let (head, _) = envelope.into_frames(&source_address).unwrap();
+        assert_eq!(head[5], FLAG_STOP_REQUEST | FLAG_DRAIN);
+
+        // The drain flag means nothing on its own
+        let mut head = Envelope::new(vec![], &dest_address, &source_address)
+            .into_frames(&source_address)
+            .unwrap()
+            .0;
+        head[5] = FLAG_DRAIN;
+        assert!(!Envelope::from(head).is_drain_request());
+    }
+
+    #[test]
+    fn codec_id() {
+        let (dest_address, source_address) = addresses();
+        let message_bytes = bincode::serialize(&42u32).unwrap();
+        let envelope = Envelope::new(message_bytes, &dest_address, &source_address);
+        assert_eq!(envelope.codec_id(), 0);
+        assert_eq!(envelope.decode::<u32>(), 42);
+
+        let envelope = Envelope::from(envelope.with_codec_id(0x7f).into_bytes());
+        assert_eq!(envelope.codec_id(), 0x7f);
+        assert!(matches!(
+            envelope.try_decode::<u32>(),
+            Err(Error::UnsupportedCodec(0x7f))
+        ));
+    }
+
+    #[test]
+    fn reject_truncated_extension() {
+        let (dest_address, source_address) = addresses();
+        let mut bytes = Envelope::new(vec![], &dest_address, &source_address).into_bytes();
+
+        // Claims a 16 byte body but only brings 3
+        bytes.extend_from_slice(&[0x7f, 16, 0, 0, 0, 0xaa, 0xbb, 0xcc]);
+        let header_length = bytes.len() as u32;
+        bytes[6..10].copy_from_slice(&header_length.to_le_bytes());
+
+        assert!(matches!(
+            Envelope::from(bytes).try_peek(),
+            Err(Error::MalformedEnvelope(_))
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    // zmq refused to create, bind, connect or use a socket
+    Transport(zmq::Error),
+    // bincode failed to (de)serialize a message
+    Serialization(bincode::Error),
+    // Received bytes do not look like an envelope
+    MalformedEnvelope(String),
+    // Envelope was produced by a peer speaking a format version we do not know
+    UnsupportedVersion(u8),
+    // Connection string is not something we know how to talk to
+    AddressParse(String),
+    // Nothing arrived within the requested time
+    Timeout,
+    // The inbox received a stop request instead of a message
+    Stopped,
+    // The envelope was tagged as a different message type than the receiver expects
+    ProtocolMismatch { expected: u64, found: u64 },
+    // A codec other than bincode failed to (de)serialize a message
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+    // The payload was encoded with a codec this build does not include
+    UnsupportedCodec(u8),
+    // The async runtime could not watch a socket
+    Io(std::io::Error),
+    // A supervisor gave up since its children failed more often than it allows
+    TooManyRestarts,
+    // Supervised children must have an address outboxes reconnect to after a restart
+    LocalChild,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(err) => write!(f, "transport error: {}", err),
+            Error::Serialization(err) => write!(f, "serialization error: {}", err),
+            Error::MalformedEnvelope(reason) => write!(f, "malformed envelope: {}", reason),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope format version {}", version)
+            }
+            Error::AddressParse(reason) => write!(f, "cannot parse address: {}", reason),
+            Error::Timeout => write!(f, "operation timed out"),
+            Error::Stopped => write!(f, "inbox was asked to stop"),
+            Error::ProtocolMismatch { expected, found } => write!(
+                f,
+                "protocol mismatch: expected message type {:016x}, got {:016x}",
+                expected, found
+            ),
+            Error::Codec(err) => write!(f, "codec error: {}", err),
+            Error::UnsupportedCodec(codec_id) => {
+                write!(f, "unsupported codec {}, is its feature enabled?", codec_id)
+            }
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::TooManyRestarts => {
+                write!(f, "child actors failed too often, supervisor gave up")
+            }
+            Error::LocalChild => {
+                write!(f, "local child actors cannot be restarted at their address")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(err) => Some(err),
+            Error::Serialization(err) => Some(err),
+            Error::Codec(err) => Some(err.as_ref()),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<zmq::Error> for Error {
+    fn from(value: zmq::Error) -> Self {
+        Error::Transport(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(value: bincode::Error) -> Self {
+        Error::Serialization(value)
+    }
+}
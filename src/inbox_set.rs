@@ -0,0 +1,314 @@
+// Waiting on several inboxes (and raw file descriptors) from a single thread.
+
+use crate::{Envelope, Error, Inbox, Result, ShouldBlock};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+// Higher priorities are served first whenever they have something to read.
+// Sources sharing a priority take turns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority(u8);
+
+impl From<u8> for Priority {
+    fn from(value: u8) -> Self {
+        Self(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum Selected {
+    // Index of the inbox as returned by InboxSet::add, and what it received
+    Envelope(usize, Envelope),
+    // The inbox received a stop request, Inbox::drain_requested tells which kind
+    Stopped(usize),
+    // The descriptor is readable, reading from it is up to the caller
+    #[cfg(unix)]
+    Fd(usize, RawFd),
+}
+
+enum Source {
+    Inbox(Inbox),
+    #[cfg(unix)]
+    Fd(RawFd),
+}
+
+struct Member {
+    source: Source,
+    priority: Priority,
+}
+
+#[derive(Default)]
+pub struct InboxSet {
+    members: Vec<Member>,
+    // Where the search for the next ready source starts, so that a busy
+    // source cannot starve the others of the same priority
+    next: usize,
+}
+
+impl InboxSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the index Selected will refer to the inbox by
+    pub fn add(&mut self, inbox: Inbox) -> usize {
+        self.add_with_priority(inbox, Priority::default())
+    }
+
+    pub fn add_with_priority(&mut self, inbox: Inbox, priority: Priority) -> usize {
+        self.push(Source::Inbox(inbox), priority)
+    }
+
+    #[cfg(unix)]
+    pub fn add_fd(&mut self, fd: RawFd) -> usize {
+        self.add_fd_with_priority(fd, Priority::default())
+    }
+
+    #[cfg(unix)]
+    pub fn add_fd_with_priority(&mut self, fd: RawFd, priority: Priority) -> usize {
+        self.push(Source::Fd(fd), priority)
+    }
+
+    pub fn inbox(&self, index: usize) -> Option<&Inbox> {
+        match self.members.get(index).map(|member| &member.source) {
+            Some(Source::Inbox(inbox)) => Some(inbox),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn select(&mut self, should_block: ShouldBlock) -> Option<Selected> {
+        self.try_select(should_block)
+            .expect("Actor failed to receive message")
+    }
+
+    pub fn try_select(&mut self, should_block: ShouldBlock) -> Result<Option<Selected>> {
+        if should_block.0 {
+            self.try_select_until(None)
+        } else {
+            self.try_select_until(Some(Instant::now()))
+        }
+    }
+
+    // Returns None if nothing arrives in time
+    pub fn select_timeout(&mut self, timeout: Duration) -> Option<Selected> {
+        match self.try_select_timeout(timeout) {
+            Err(Error::Timeout) => None,
+            result => Some(result.expect("Actor failed to receive message")),
+        }
+    }
+
+    // Fails with Error::Timeout if nothing arrives in time
+    pub fn try_select_timeout(&mut self, timeout: Duration) -> Result<Selected> {
+        self.try_select_until(Some(Instant::now() + timeout))?
+            .ok_or(Error::Timeout)
+    }
+
+    fn push(&mut self, source: Source, priority: Priority) -> usize {
+        self.members.push(Member { source, priority });
+        self.members.len() - 1
+    }
+
+    // Ok(None) means the deadline passed before anything arrived,
+    // no deadline blocks until something does
+    fn try_select_until(&mut self, deadline: Option<Instant>) -> Result<Option<Selected>> {
+        loop {
+            let timeout_ms = match deadline {
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_micros()
+                    .div_ceil(1000)
+                    .min(i64::MAX as u128) as i64,
+                None => -1,
+            };
+
+            let ready = self.poll(timeout_ms)?;
+            let index = match self.pick(&ready) {
+                Some(index) => index,
+                None if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    return Ok(None)
+                }
+                None => continue,
+            };
+
+            self.next = (index + 1) % self.members.len();
+            match &self.members[index].source {
+                Source::Inbox(inbox) => {
+                    if let Some(envelope) = inbox.try_receive_envelope(ShouldBlock::from(false))? {
+                        return Ok(Some(Selected::Envelope(index, envelope)));
+                    }
+                    if inbox.stop_requested() {
+                        return Ok(Some(Selected::Stopped(index)));
+                    }
+                    // Otherwise another reader was quicker, so we wait again
+                }
+                #[cfg(unix)]
+                Source::Fd(fd) => return Ok(Some(Selected::Fd(index, *fd))),
+            }
+        }
+    }
+
+    // Tells for every member whether it has something to read
+    fn poll(&self, timeout_ms: i64) -> Result<Vec<bool>> {
+        let mut items: Vec<_> = self
+            .members
+            .iter()
+            .map(|member| match &member.source {
+                Source::Inbox(inbox) => inbox.control_socket.as_poll_item(zmq::POLLIN),
+                #[cfg(unix)]
+                Source::Fd(fd) => zmq::PollItem::from_fd(*fd, zmq::POLLIN),
+            })
+            .collect();
+
+        zmq::poll(&mut items, timeout_ms)?;
+        Ok(items.iter().map(zmq::PollItem::is_readable).collect())
+    }
+
+    // The highest priority wins, ties go to whoever comes first starting from `next`
+    fn pick(&self, ready: &[bool]) -> Option<usize> {
+        let count = self.members.len();
+
+        (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .filter(|&index| ready[index])
+            .fold(None, |best: Option<usize>, index| match best {
+                Some(best) if self.members[best].priority >= self.members[index].priority => {
+                    Some(best)
+                }
+                _ => Some(index),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InboxSet, Priority, Selected};
+    use crate::{AddressType, Envelope, Inbox, Outbox, ShouldBlock};
+    use std::time::Duration;
+
+    fn envelope_payload(selected: Option<Selected>) -> (usize, Vec<u8>) {
+        match selected {
+            Some(Selected::Envelope(index, envelope)) => {
+                let (_, _, message_bytes) = envelope.open();
+                (index, message_bytes)
+            }
+            other => panic!("Expected an envelope, got {:?}", other),
+        }
+    }
+
+    fn send(ctx: &zmq::Context, inbox: &Inbox, message_bytes: Vec<u8>) {
+        Outbox::new(ctx.clone(), inbox.address(), inbox.address()).send_envelope(Envelope::new(
+            message_bytes,
+            inbox.address(),
+            inbox.address(),
+        ));
+    }
+
+    #[test]
+    fn control_preempts_data() {
+        let ctx = zmq::Context::new();
+        let data_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let control_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        for i in 0..3 {
+            send(&ctx, &data_inbox, vec![i]);
+        }
+        send(&ctx, &control_inbox, vec![42]);
+
+        let mut inbox_set = InboxSet::new();
+        let data = inbox_set.add(data_inbox);
+        let control = inbox_set.add_with_priority(control_inbox, Priority::from(1));
+
+        let block = || ShouldBlock::from(true);
+        assert_eq!(
+            envelope_payload(inbox_set.select(block())),
+            (control, vec![42])
+        );
+        for i in 0..3 {
+            assert_eq!(envelope_payload(inbox_set.select(block())), (data, vec![i]));
+        }
+        assert!(inbox_set.select(ShouldBlock::from(false)).is_none());
+    }
+
+    #[test]
+    fn equal_priorities_take_turns() {
+        let ctx = zmq::Context::new();
+        let first_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let second_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        for i in 0..2 {
+            send(&ctx, &first_inbox, vec![i]);
+            send(&ctx, &second_inbox, vec![i]);
+        }
+
+        let mut inbox_set = InboxSet::new();
+        let first = inbox_set.add(first_inbox);
+        let second = inbox_set.add(second_inbox);
+
+        let mut order = Vec::new();
+        while let Some(selected) = inbox_set.select_timeout(Duration::from_millis(50)) {
+            order.push(envelope_payload(Some(selected)));
+        }
+        assert_eq!(
+            order,
+            vec![
+                (first, vec![0]),
+                (second, vec![0]),
+                (first, vec![1]),
+                (second, vec![1])
+            ]
+        );
+    }
+
+    #[test]
+    fn report_stop_requests() {
+        let ctx = zmq::Context::new();
+        let first_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let second_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        Outbox::new(ctx, second_inbox.address(), second_inbox.address()).send_envelope(
+            Envelope::drain_request(second_inbox.address(), second_inbox.address()),
+        );
+
+        let mut inbox_set = InboxSet::new();
+        inbox_set.add(first_inbox);
+        let second = inbox_set.add(second_inbox);
+
+        assert!(matches!(
+            inbox_set.select(ShouldBlock::from(true)),
+            Some(Selected::Stopped(index)) if index == second
+        ));
+        assert!(inbox_set.inbox(second).unwrap().drain_requested());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn select_fd() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        let ctx = zmq::Context::new();
+        let (mut writer, reader) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        let mut inbox_set = InboxSet::new();
+        inbox_set.add(Inbox::bind_new(ctx, AddressType::Local));
+        let fd = inbox_set.add_fd(reader.as_raw_fd());
+        assert!(inbox_set
+            .select_timeout(Duration::from_millis(10))
+            .is_none());
+
+        writer.write_all(&[1]).unwrap();
+        assert!(matches!(
+            inbox_set.select_timeout(Duration::from_secs(10)),
+            Some(Selected::Fd(index, raw_fd)) if index == fd && raw_fd == reader.as_raw_fd()
+        ));
+    }
+}
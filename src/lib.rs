@@ -1,14 +1,51 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 
 pub use custom_derive::actor_message;
 
-const ADDRESS_LENGTH: usize = 32;
+// #[actor_message] refers to everything as ::yocto_actor::..., which has to
+// resolve within this crate as well
+extern crate self as yocto_actor;
+
+#[cfg(all(feature = "async", unix))]
+mod asynchronous;
+mod codec;
+mod context;
+mod envelope;
+mod error;
+mod inbox_set;
+mod scheduler;
+mod supervisor;
+mod system;
+#[cfg(test)]
+mod test_actors;
+
+#[cfg(all(feature = "async", unix))]
+pub use asynchronous::{AsyncInbox, AsyncOutbox};
+#[cfg(feature = "cbor")]
+pub use codec::Cbor;
+#[cfg(feature = "json")]
+pub use codec::Json;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePack;
+pub use codec::{Bincode, Codec};
+pub use context::Context;
+pub use envelope::{Envelope, Headers};
+pub use error::{Error, Result};
+pub use inbox_set::{InboxSet, Priority, Selected};
+pub use scheduler::{Scheduled, Scheduler};
+pub use supervisor::{ChildExit, RestartStrategy, Supervisor};
+pub use system::{Actor, ActorRef, ActorSystem};
+
+// Length of the generated part of Local and Ipc addresses
+const ADDRESS_NAME_LENGTH: usize = 23;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Address {
-    // #[serde(with = "serde_bytes")]
-    conn_string: [u8; ADDRESS_LENGTH],
+    conn_string: String,
 }
 
 impl std::fmt::Display for Address {
@@ -17,55 +54,143 @@ impl std::fmt::Display for Address {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AddressType {
     // ToDo: probably this should be renamed so that it's clear
     // that it's an inproc address, not a local IP one
     Local,
     Remote,
+    // Unix domain socket, only makes sense between processes on the same host
+    Ipc,
+}
+
+impl AddressType {
+    fn scheme(self) -> &'static str {
+        match self {
+            AddressType::Local => "inproc://",
+            AddressType::Remote => "tcp://",
+            AddressType::Ipc => "ipc://",
+        }
+    }
 }
 
-// ToDo: impl From<std::net::IpAddr>
 impl Address {
     pub fn new(address_type: AddressType) -> Self {
-        let mut conn_string = [0 as u8; ADDRESS_LENGTH];
+        Self::try_new(address_type).expect("Cannot create address")
+    }
 
+    pub fn try_new(address_type: AddressType) -> Result<Self> {
         match address_type {
-            AddressType::Local => {
-                write!(
-                    &mut conn_string[..],
-                    "inproc://{}",
-                    silly_names::make_name(ADDRESS_LENGTH - "inproc://".len())
-                )
-                .expect("Cannot create address");
-
-                Self { conn_string }
-            }
-            AddressType::Remote => {
-                write!(
-                    &mut conn_string[..],
-                    "tcp://127.0.0.1:{}",
-                    5000 + rand::random::<u64>() % 5000
-                )
-                .expect("Cannot create address");
-
-                Self { conn_string }
-            }
+            AddressType::Local => Self::try_from_conn_string(&format!(
+                "inproc://{}",
+                silly_names::make_name(ADDRESS_NAME_LENGTH)
+            )),
+            AddressType::Remote => Self::try_from_conn_string(&format!(
+                "tcp://127.0.0.1:{}",
+                5000 + rand::random::<u64>() % 5000
+            )),
+            AddressType::Ipc => Self::try_from_conn_string(&format!(
+                "ipc:///tmp/{}",
+                silly_names::make_name(ADDRESS_NAME_LENGTH)
+            )),
         }
     }
 
+    fn try_from_conn_string(value: &str) -> Result<Self> {
+        parse_conn_string(value)?;
+
+        Ok(Self {
+            conn_string: value.to_owned(),
+        })
+    }
+
     pub fn get_type(&self) -> AddressType {
-        match self.conn_string[..3] {
-            // 'inp'
-            [0x69, 0x6e, 0x70] => AddressType::Local,
-            // 'tcp'
-            [0x74, 0x63, 0x70] => AddressType::Remote,
-            _ => panic!("Address connection string is malformed"),
-        }
+        self.try_get_type()
+            .expect("Address connection string is malformed")
+    }
+
+    pub fn try_get_type(&self) -> Result<AddressType> {
+        parse_conn_string(&self.conn_string)
     }
 
     pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.conn_string)
-            .expect("Address connection string is not valid utf-8")
+        &self.conn_string
+    }
+
+    fn is_ipv6(&self) -> bool {
+        self.conn_string.starts_with("tcp://[")
+    }
+}
+
+// Checks that the string is something zmq can bind to and that it fits into our wire format
+fn parse_conn_string(value: &str) -> Result<AddressType> {
+    let error = |reason: &str| Err(Error::AddressParse(format!("{:?} {}", value, reason)));
+
+    if value.len() > u16::MAX as usize {
+        return error(&format!("is longer than {} bytes", u16::MAX));
+    }
+
+    if value.contains('\0') {
+        return error("contains a zero byte");
+    }
+
+    let address_type = match [AddressType::Local, AddressType::Remote, AddressType::Ipc]
+        .iter()
+        .find(|address_type| value.starts_with(address_type.scheme()))
+    {
+        Some(&address_type) => address_type,
+        None => return error("does not start with inproc://, tcp:// or ipc://"),
+    };
+
+    let rest = &value[address_type.scheme().len()..];
+    if rest.is_empty() {
+        return error("has an empty endpoint");
+    }
+
+    if let AddressType::Remote = address_type {
+        let (host, port) = match rest.rfind(':') {
+            Some(colon_position) => (&rest[..colon_position], &rest[colon_position + 1..]),
+            None => return error("has no port"),
+        };
+
+        if host.is_empty() {
+            return error("has no host");
+        }
+
+        if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+            return error("has an IPv6 host without brackets");
+        }
+
+        // '*' asks zmq to pick a free port on bind
+        if port != "*" && port.parse::<u16>().is_err() {
+            return error("has an invalid port");
+        }
+    }
+
+    Ok(address_type)
+}
+
+impl std::str::FromStr for Address {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Self::try_from_conn_string(value)
+    }
+}
+
+impl std::convert::TryFrom<&str> for Address {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        Self::try_from_conn_string(value)
+    }
+}
+
+impl From<std::net::SocketAddr> for Address {
+    fn from(value: std::net::SocketAddr) -> Self {
+        Self {
+            conn_string: format!("tcp://{}", value),
+        }
     }
 }
 
@@ -112,7 +237,48 @@ impl std::fmt::Display for SourceAddress {
     }
 }
 
-pub trait Message: serde::Serialize {}
+pub trait Message: serde::Serialize {
+    // Sent along with every message so that receivers expecting another type
+    // can tell. #[actor_message] derives it from the enum name and layout.
+    const TYPE_TAG: Option<u64> = None;
+
+    // The tag actually sent and checked. Instances of a generic enum share
+    // TYPE_TAG, so #[actor_message] mixes their type parameters in here.
+    fn type_tag() -> Option<u64> {
+        Self::TYPE_TAG
+    }
+}
+
+// Serializes the message into a new envelope tagged with its type and codec
+fn message_envelope<M: Message>(
+    message: &M,
+    codec_id: u8,
+    dest_address: &Address,
+    source_address: &Address,
+) -> Result<Envelope> {
+    let envelope = Envelope::new(
+        codec::encode(codec_id, message)?,
+        dest_address,
+        source_address,
+    )
+    .try_with_codec_id(codec_id)?;
+    match M::type_tag() {
+        Some(type_tag) => envelope.try_with_type_tag(type_tag),
+        None => Ok(envelope),
+    }
+}
+
+// The protocol of an Outbox that sends messages of any type, which is what
+// Outbox::new hands out
+pub enum AnyMessage {}
+
+// Tells which messages an Outbox<P> may send: an Outbox<M> only sends M,
+// an Outbox<AnyMessage> sends anything
+pub trait Accepts<M> {}
+
+impl<M: Message> Accepts<M> for AnyMessage {}
+
+impl<M: Message> Accepts<M> for M {}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ShouldBlock(bool);
@@ -123,152 +289,486 @@ impl From<bool> for ShouldBlock {
     }
 }
 
-fn truncate_byte_array_string(bytes: &[u8]) -> &str {
-    // zmq converts our &str into a CString so it gets mad when
-    // we pass a string with zero bytes
-    let zero_byte_position = bytes.iter().position(|&v| v == 0).unwrap_or(bytes.len());
-    let truncated_bytes = &bytes[..zero_byte_position];
-    std::str::from_utf8(truncated_bytes).expect("Truncated string is not valid utf-8")
-}
+// How many freshly generated addresses Inbox::bind_random tries before giving up
+const MAX_BIND_ATTEMPTS: usize = 16;
 
 pub struct Inbox {
+    zmq_ctx: zmq::Context,
     control_socket: zmq::Socket,
+    address: Address,
+    // Set once a stop request arrived, see Envelope::stop_request
+    stop_requested: Cell<bool>,
+    // Set along with stop_requested by Envelope::drain_request
+    drain_requested: Cell<bool>,
+    // Stop requests meant for another generation are ignored, see with_generation
+    generation: Option<u64>,
 }
 
 impl Inbox {
     pub fn new(zmq_ctx: zmq::Context, address: &Address) -> Self {
-        let control_socket = zmq_ctx
-            .socket(zmq::PULL)
-            .expect("Cannot create control socket");
+        Self::try_new(zmq_ctx, address).expect("Cannot create inbox")
+    }
+
+    pub fn try_new(zmq_ctx: zmq::Context, address: &Address) -> Result<Self> {
+        let control_socket = zmq_ctx.socket(zmq::PULL)?;
+        control_socket.set_ipv6(address.is_ipv6())?;
+        control_socket.bind(address.as_str())?;
+
+        // When bound to tcp://host:* zmq picks the port itself, so we
+        // ask it where we actually ended up
+        let address = match control_socket.get_last_endpoint()? {
+            Ok(endpoint) => Address::try_from_conn_string(&endpoint)?,
+            Err(_) => {
+                return Err(Error::AddressParse(
+                    "bound endpoint is not valid utf-8".to_owned(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            zmq_ctx,
+            control_socket,
+            address,
+            stop_requested: Cell::new(false),
+            drain_requested: Cell::new(false),
+            generation: None,
+        })
+    }
+
+    // A supervisor binds the same address again for every restart of a child.
+    // A stop request it sent to an incarnation that was already gone may only
+    // arrive at the next one, so its stop requests name the generation they
+    // are meant for. Untagged stop requests stop any generation.
+    pub(crate) fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = Some(generation);
+        self
+    }
 
-        control_socket
-            .bind(truncate_byte_array_string(&address.conn_string))
-            .expect("Cannot connect control socket");
+    // Binds to a fresh address of the given type. Remote inboxes let zmq
+    // pick a free port, so there is nothing to retry
+    pub fn bind_new(zmq_ctx: zmq::Context, address_type: AddressType) -> Self {
+        Self::try_bind_new(zmq_ctx, address_type).expect("Cannot create inbox")
+    }
+
+    pub fn try_bind_new(zmq_ctx: zmq::Context, address_type: AddressType) -> Result<Self> {
+        match address_type {
+            AddressType::Remote => Self::try_new(
+                zmq_ctx,
+                &Address::try_from_conn_string("tcp://127.0.0.1:*")?,
+            ),
+            AddressType::Local | AddressType::Ipc => Self::try_bind_random(zmq_ctx, address_type),
+        }
+    }
+
+    // Binds to a randomly generated address of the given type, generating
+    // a new one whenever the previous is already taken
+    pub fn bind_random(zmq_ctx: zmq::Context, address_type: AddressType) -> Self {
+        Self::try_bind_random(zmq_ctx, address_type).expect("Cannot create inbox")
+    }
 
-        Self { control_socket }
+    pub fn try_bind_random(zmq_ctx: zmq::Context, address_type: AddressType) -> Result<Self> {
+        let mut attempts_left = MAX_BIND_ATTEMPTS;
+        loop {
+            attempts_left -= 1;
+            match Self::try_new(zmq_ctx.clone(), &Address::try_new(address_type)?) {
+                Err(Error::Transport(zmq::Error::EADDRINUSE)) if attempts_left > 0 => continue,
+                result => return result,
+            }
+        }
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
     }
 
+    // Receives return None for a stop request, this tells it apart from
+    // nothing having arrived
+    pub fn stop_requested(&self) -> bool {
+        self.stop_requested.get()
+    }
+
+    // Whether the stop request asked to handle the queued messages first.
+    // They can still be received as usual.
+    pub fn drain_requested(&self) -> bool {
+        self.drain_requested.get()
+    }
+
+    // Hands out the envelope as a single buffer, which costs a copy of the
+    // payload. Prefer receive_envelope where that matters.
     pub fn receive(&self, should_block: ShouldBlock) -> Option<Vec<u8>> {
-        match self.control_socket.recv_bytes(if should_block.0 {
-            0
-        } else {
-            // This is actually bad since we should have used ZMQ_NOBLOCK here,
-            // but zmq crate does not expose it :( Fortunately, integer values
-            // of these enum variants coincide
-            zmq::DONTWAIT
-        }) {
-            Ok(bytes) => Some(bytes),
-            Err(err) => match err {
-                zmq::Error::EAGAIN => None,
-                _ => panic!("Actor failed to receive message"),
-            },
+        self.try_receive(should_block)
+            .expect("Actor failed to receive message")
+    }
+
+    pub fn try_receive(&self, should_block: ShouldBlock) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .try_receive_envelope(should_block)?
+            .map(Envelope::into_bytes))
+    }
+
+    pub fn receive_envelope(&self, should_block: ShouldBlock) -> Option<Envelope> {
+        self.try_receive_envelope(should_block)
+            .expect("Actor failed to receive message")
+    }
+
+    pub fn try_receive_envelope(&self, should_block: ShouldBlock) -> Result<Option<Envelope>> {
+        loop {
+            let head = match self.control_socket.recv_bytes(if should_block.0 {
+                0
+            } else {
+                // This is actually bad since we should have used ZMQ_NOBLOCK here,
+                // but zmq crate does not expose it :( Fortunately, integer values
+                // of these enum variants coincide
+                zmq::DONTWAIT
+            }) {
+                Ok(bytes) => bytes,
+                Err(zmq::Error::EAGAIN) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+
+            // Older peers send everything in one frame
+            let envelope = if self.control_socket.get_rcvmore()? {
+                // zmq delivers multipart messages atomically, so the rest is already here
+                let payload = self.control_socket.recv_msg(0)?;
+
+                if self.control_socket.get_rcvmore()? {
+                    while self.control_socket.get_rcvmore()? {
+                        self.control_socket.recv_msg(0)?;
+                    }
+
+                    return Err(Error::MalformedEnvelope(
+                        "expected at most two frames".to_owned(),
+                    ));
+                }
+
+                Envelope::from_frames(head, payload)
+            } else {
+                Envelope::from(head)
+            };
+
+            if !self.is_stale_stop_request(&envelope) {
+                return Ok(self.unless_stop_request(envelope));
+            }
+        }
+    }
+
+    fn is_stale_stop_request(&self, envelope: &Envelope) -> bool {
+        envelope.is_stop_request()
+            && envelope
+                .stop_generation()
+                .is_some_and(|generation| Some(generation) != self.generation)
+    }
+
+    fn unless_stop_request(&self, envelope: Envelope) -> Option<Envelope> {
+        if envelope.is_stop_request() {
+            self.stop_requested.set(true);
+            self.drain_requested.set(envelope.is_drain_request());
+            return None;
+        }
+
+        Some(envelope)
+    }
+
+    // Returns None if nothing arrives in time
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        match self.try_receive_timeout(timeout) {
+            Err(Error::Timeout) | Err(Error::Stopped) => None,
+            result => Some(result.expect("Actor failed to receive message")),
+        }
+    }
+
+    pub fn try_receive_timeout(&self, timeout: Duration) -> Result<Vec<u8>> {
+        Ok(self.try_receive_envelope_timeout(timeout)?.into_bytes())
+    }
+
+    pub fn receive_envelope_timeout(&self, timeout: Duration) -> Option<Envelope> {
+        match self.try_receive_envelope_timeout(timeout) {
+            Err(Error::Timeout) | Err(Error::Stopped) => None,
+            result => Some(result.expect("Actor failed to receive message")),
+        }
+    }
+
+    // Fails with Error::Timeout if nothing arrives in time
+    // and with Error::Stopped if a stop request does
+    pub fn try_receive_envelope_timeout(&self, timeout: Duration) -> Result<Envelope> {
+        match self.try_receive_envelope_until(Instant::now() + timeout)? {
+            Some(envelope) => Ok(envelope),
+            None if self.stop_requested() => Err(Error::Stopped),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    // Ok(None) means the deadline passed before anything arrived,
+    // or that a stop request did
+    fn try_receive_envelope_until(&self, deadline: Instant) -> Result<Option<Envelope>> {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            // Round up, otherwise we would spin through the last millisecond
+            let timeout_ms = timeout.as_micros().div_ceil(1000).min(i64::MAX as u128) as i64;
+
+            if self.control_socket.poll(zmq::POLLIN, timeout_ms)? == 0 {
+                if Instant::now() >= deadline {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            if let Some(envelope) = self.try_receive_envelope(ShouldBlock::from(false))? {
+                return Ok(Some(envelope));
+            }
+            if self.stop_requested() {
+                return Ok(None);
+            }
         }
     }
+
+    // Sends the response back to whoever sent the envelope, carrying over its
+    // correlation id so that Outbox::ask can match the two up
+    pub fn reply<R: Message>(&self, envelope: &Envelope, response: &R) {
+        self.try_reply(envelope, response)
+            .expect("Cannot send reply");
+    }
+
+    pub fn try_reply<R: Message>(&self, envelope: &Envelope, response: &R) -> Result<()> {
+        Context::try_new(self, envelope)?.try_reply(response)
+    }
 }
 
-pub struct Outbox {
+pub struct Outbox<P = AnyMessage> {
+    zmq_ctx: zmq::Context,
     control_socket: zmq::Socket,
     dest_address: Address,
     source_address: Address,
+    codec_id: u8,
+    protocol: PhantomData<fn(P)>,
 }
 
 impl Outbox {
     // ToDo: yeah, this duplication is sad, but will do for now
     pub fn new(zmq_ctx: zmq::Context, dest_address: &Address, source_address: &Address) -> Self {
-        let control_socket = zmq_ctx
-            .socket(zmq::PUSH)
-            .expect("Cannot create control socket");
-        control_socket
-            .connect(truncate_byte_array_string(&dest_address.conn_string))
-            .expect("Cannot connect control socket");
+        Self::try_new(zmq_ctx, dest_address, source_address).expect("Cannot create outbox")
+    }
 
-        Self {
+    pub fn try_new(
+        zmq_ctx: zmq::Context,
+        dest_address: &Address,
+        source_address: &Address,
+    ) -> Result<Self> {
+        let control_socket = zmq_ctx.socket(zmq::PUSH)?;
+        control_socket.set_ipv6(dest_address.is_ipv6())?;
+        control_socket.connect(dest_address.as_str())?;
+
+        Ok(Self {
+            zmq_ctx,
             control_socket,
             dest_address: dest_address.clone(),
             source_address: source_address.clone(),
+            codec_id: Bincode::ID,
+            protocol: PhantomData,
+        })
+    }
+
+    // Restricts the outbox to the messages its destination handles, so that
+    // sending anything else no longer compiles
+    pub fn typed<M: Message>(self) -> Outbox<M> {
+        Outbox {
+            zmq_ctx: self.zmq_ctx,
+            control_socket: self.control_socket,
+            dest_address: self.dest_address,
+            source_address: self.source_address,
+            codec_id: self.codec_id,
+            protocol: PhantomData,
         }
     }
+}
 
-    pub fn send_message<M: Message>(&self, message: &M) {
-        let message_bytes = bincode::serialize(message).expect("Cannot serialize message");
+impl<P> Outbox<P> {
+    // Messages are encoded with bincode unless told otherwise. Receivers
+    // follow whatever codec the envelope names.
+    pub fn with_codec<C: Codec>(self) -> Self {
+        self.with_codec_id(C::ID)
+    }
 
-        let envelope = Envelope::new(message_bytes, &self.dest_address, &self.source_address);
-        self.send_envelope(&envelope);
+    pub(crate) fn with_codec_id(mut self, codec_id: u8) -> Self {
+        self.codec_id = codec_id;
+        self
     }
 
-    pub fn send_envelope(&self, envelope: &Envelope) {
-        self.control_socket
-            .send(&envelope.0, 0)
-            .expect("Cannot send message to worker");
+    // How long dropping the outbox may hold up zmq in the background while
+    // messages it queued are still on their way, forever if None. zmq
+    // defaults to forever, which flushes every send but also keeps
+    // zmq::Context from being dropped while a destination is unreachable.
+    // Zero drops whatever is not sent yet.
+    pub fn with_linger(self, linger: Option<Duration>) -> Self {
+        self.try_with_linger(linger)
+            .expect("Cannot set outbox linger")
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Envelope(Vec<u8>);
+    pub fn try_with_linger(self, linger: Option<Duration>) -> Result<Self> {
+        let linger_ms = match linger {
+            Some(linger) => linger.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        self.control_socket.set_linger(linger_ms)?;
+        Ok(self)
+    }
 
-impl Envelope {
-    pub fn new(
-        mut message_bytes: Vec<u8>,
-        dest_address: &Address,
-        source_address: &Address,
-    ) -> Self {
-        message_bytes.extend(source_address.conn_string.iter());
-        message_bytes.extend(dest_address.conn_string.iter());
+    pub fn linger(&self) -> Option<Duration> {
+        self.try_linger().expect("Cannot get outbox linger")
+    }
 
-        Self::from(message_bytes)
+    pub fn try_linger(&self) -> Result<Option<Duration>> {
+        let linger_ms = self.control_socket.get_linger()?;
+        Ok(if linger_ms < 0 {
+            None
+        } else {
+            Some(Duration::from_millis(linger_ms as u64))
+        })
     }
 
-    pub fn open(mut self) -> (DestAddress, SourceAddress, Vec<u8>) {
-        let mut dest_address = [0 as u8; ADDRESS_LENGTH];
-        for (idx, byte) in self.0.drain(self.0.len() - ADDRESS_LENGTH..).enumerate() {
-            dest_address[idx] = byte;
-        }
+    pub fn send_message<M: Message>(&self, message: &M)
+    where
+        P: Accepts<M>,
+    {
+        self.try_send_message(message)
+            .expect("Cannot send message to worker");
+    }
 
-        let mut source_address = [0 as u8; ADDRESS_LENGTH];
-        for (idx, byte) in self.0.drain(self.0.len() - ADDRESS_LENGTH..).enumerate() {
-            source_address[idx] = byte;
-        }
+    pub fn try_send_message<M: Message>(&self, message: &M) -> Result<()>
+    where
+        P: Accepts<M>,
+    {
+        self.try_send_message_with_headers(message, &Headers::new())
+    }
 
-        (
-            Address {
-                conn_string: dest_address,
-            }
-            .into(),
-            Address {
-                conn_string: source_address,
-            }
-            .into(),
-            self.0,
+    pub fn send_message_with_headers<M: Message>(&self, message: &M, headers: &Headers)
+    where
+        P: Accepts<M>,
+    {
+        self.try_send_message_with_headers(message, headers)
+            .expect("Cannot send message to worker");
+    }
+
+    pub fn try_send_message_with_headers<M: Message>(
+        &self,
+        message: &M,
+        headers: &Headers,
+    ) -> Result<()>
+    where
+        P: Accepts<M>,
+    {
+        self.try_send_envelope(self.envelope_for(message, headers)?)
+    }
+
+    fn envelope_for<M: Message>(&self, message: &M, headers: &Headers) -> Result<Envelope> {
+        headers.iter().try_fold(
+            message_envelope(
+                message,
+                self.codec_id,
+                &self.dest_address,
+                &self.source_address,
+            )?,
+            |envelope, (key, value)| envelope.try_with_header(key, value),
         )
     }
 
-    pub fn peek(&self) -> (DestAddress, SourceAddress) {
-        use std::convert::TryInto;
-        (
-            Address {
-                conn_string: (&self.0[self.0.len() - ADDRESS_LENGTH..])
-                    .try_into()
-                    .expect("Cannot copy destination address from payload"),
-            }
-            .into(),
-            Address {
-                conn_string: (&self.0
-                    [self.0.len() - ADDRESS_LENGTH * 2..self.0.len() - ADDRESS_LENGTH])
-                    .try_into()
-                    .expect("Cannot copy source address from payload"),
+    // Sends the message and waits for the reply. A temporary inbox of the same
+    // type as our destination is bound for the reply and used as the source
+    // address, which only works for tcp if the peer can reach 127.0.0.1.
+    // Use ask_via with an inbox the peer can reach otherwise.
+    pub fn ask<M: Message, R: Message + DeserializeOwned>(
+        &self,
+        message: &M,
+        timeout: Duration,
+    ) -> R
+    where
+        P: Accepts<M>,
+    {
+        self.try_ask(message, timeout)
+            .expect("Cannot get reply from worker")
+    }
+
+    pub fn try_ask<M: Message, R: Message + DeserializeOwned>(
+        &self,
+        message: &M,
+        timeout: Duration,
+    ) -> Result<R>
+    where
+        P: Accepts<M>,
+    {
+        let reply_inbox =
+            Inbox::try_bind_new(self.zmq_ctx.clone(), self.dest_address.try_get_type()?)?;
+        self.try_ask_via(message, &reply_inbox, timeout)
+    }
+
+    pub fn ask_via<M: Message, R: Message + DeserializeOwned>(
+        &self,
+        message: &M,
+        reply_inbox: &Inbox,
+        timeout: Duration,
+    ) -> R
+    where
+        P: Accepts<M>,
+    {
+        self.try_ask_via(message, reply_inbox, timeout)
+            .expect("Cannot get reply from worker")
+    }
+
+    // The reply inbox may be shared between several asks made one after another.
+    // Replies that do not match our correlation id, e.g. late answers to asks
+    // that already timed out, are dropped. A reply of another type than R is
+    // an Error::ProtocolMismatch.
+    pub fn try_ask_via<M: Message, R: Message + DeserializeOwned>(
+        &self,
+        message: &M,
+        reply_inbox: &Inbox,
+        timeout: Duration,
+    ) -> Result<R>
+    where
+        P: Accepts<M>,
+    {
+        let deadline = Instant::now() + timeout;
+        let correlation_id = rand::random::<u64>();
+
+        let envelope = message_envelope(
+            message,
+            self.codec_id,
+            &self.dest_address,
+            reply_inbox.address(),
+        )?
+        .try_with_correlation_id(correlation_id)?;
+        self.try_send_envelope(envelope)?;
+
+        loop {
+            let envelope = match reply_inbox.try_receive_envelope_until(deadline)? {
+                Some(envelope) => envelope,
+                None => return Err(Error::Timeout),
+            };
+
+            if envelope.correlation_id() == Some(correlation_id) {
+                envelope.try_check_type_tag::<R>()?;
+                return envelope.try_decode();
             }
-            .into(),
-        )
+        }
     }
-}
 
-impl From<Vec<u8>> for Envelope {
-    fn from(bytes: Vec<u8>) -> Self {
-        Self(bytes)
+    // Addresses the envelope to our destination and sends it on. The original
+    // source is kept, so this is how forwarding actors pass messages along.
+    // What the envelope carries is not checked against the protocol.
+    pub fn send_envelope(&self, envelope: Envelope) {
+        self.try_send_envelope(envelope)
+            .expect("Cannot send message to worker");
+    }
+
+    pub fn try_send_envelope(&self, envelope: Envelope) -> Result<()> {
+        let (head, payload) = envelope.into_frames(&self.dest_address)?;
+
+        self.control_socket.send(head, zmq::SNDMORE)?;
+        self.control_socket.send(payload, 0)?;
+        Ok(())
     }
 }
 
-// ToDo: Result?
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ShouldTerminate(bool);
 
@@ -278,18 +778,34 @@ impl From<bool> for ShouldTerminate {
     }
 }
 
-impl Into<bool> for ShouldTerminate {
-    fn into(self) -> bool {
-        self.0
+impl From<ShouldTerminate> for bool {
+    fn from(value: ShouldTerminate) -> Self {
+        value.0
     }
 }
 
+// What the run loop does after a handler generated with
+// #[actor_message(error = ...)] returned an error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    // Drop the failed message and go on with the next one
+    Continue,
+    // Leave the run loop, try_run returns the error then
+    Stop,
+    // Call on_restart so that the actor can reset its state, then go on.
+    // pre_restart and post_restart are called right before and after it.
+    Restart,
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        Address, AddressType, Envelope, Inbox, Message, Outbox, ShouldBlock, ShouldTerminate,
+        Address, AddressType, Context, Envelope, Error, Headers, Inbox, Message, Outbox,
+        ShouldBlock, ShouldTerminate,
     };
     use serde::{Deserialize, Serialize};
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
 
     #[derive(Serialize, Deserialize)]
     enum FirstMessageType {
@@ -499,37 +1015,33 @@ mod tests {
 
     struct DerivedWorker {
         inbox: Inbox,
-        next_stage_outbox: Outbox,
+        next_stage: SecondMessageTypeClient,
         payload: u64,
+        headers: Headers,
     }
 
     impl SecondMessageTypeHandler for DerivedWorker {
-        fn receive(&self) -> SecondMessageType {
-            let envelope = Envelope::from(
-                self.inbox
-                    .receive(ShouldBlock::from(true))
-                    .expect("Cannot receive message"),
-            );
-            let (_, _, message_bytes) = envelope.open();
-
-            let message: SecondMessageType =
-                bincode::deserialize(&message_bytes).expect("Actor cannot deserialize envelope");
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
 
-            message
+        fn on_headers(&mut self, headers: &Headers) {
+            self.headers = headers.clone();
         }
 
         fn handle_message_a(&mut self) -> ShouldTerminate {
-            self.next_stage_outbox
-                .send_message(&FirstMessageType::MessageA);
+            self.next_stage.send_message_a();
             ShouldTerminate::from(true)
         }
 
         fn handle_message_b(&mut self, c_foo: u64, c_bar: String) -> ShouldTerminate {
-            self.next_stage_outbox
-                .send_message(&FirstMessageType::MessageB {
+            self.next_stage.outbox().send_message_with_headers(
+                &SecondMessageType::MessageB {
                     c_foo: c_foo + self.payload,
                     c_bar,
-                });
+                },
+                &self.headers,
+            );
             ShouldTerminate::from(false)
         }
 
@@ -544,11 +1056,28 @@ mod tests {
             worker_address: &Address,
             next_stage_address: &Address,
             payload: u64,
+        ) -> Self {
+            Self::with_inbox(
+                zmq_ctx.clone(),
+                Inbox::new(zmq_ctx, worker_address),
+                next_stage_address,
+                payload,
+            )
+        }
+
+        fn with_inbox(
+            zmq_ctx: zmq::Context,
+            inbox: Inbox,
+            next_stage_address: &Address,
+            payload: u64,
         ) -> Self {
             Self {
-                inbox: Inbox::new(zmq_ctx.clone(), worker_address),
-                next_stage_outbox: Outbox::new(zmq_ctx, next_stage_address, worker_address),
+                next_stage: SecondMessageTypeClient::new(
+                    Outbox::new(zmq_ctx, next_stage_address, inbox.address()).typed(),
+                ),
+                inbox,
                 payload,
+                headers: Headers::new(),
             }
         }
     }
@@ -602,24 +1131,31 @@ mod tests {
             })
         };
 
-        let outbox = Outbox::new(ctx.clone(), &first_worker_address, &spawner_address);
-        outbox.send_message(&FirstMessageType::MessageB {
-            c_foo: 50,
-            c_bar: "Ta-da-da".to_owned(),
-        });
+        let client = SecondMessageTypeClient::from(
+            Outbox::new(ctx.clone(), &first_worker_address, &spawner_address).typed(),
+        );
+        let mut headers = Headers::new();
+        headers.insert("tenant".to_owned(), "acme".to_owned());
+        client.outbox().send_message_with_headers(
+            &SecondMessageType::MessageB {
+                c_foo: 50,
+                c_bar: "Ta-da-da".to_owned(),
+            },
+            &headers,
+        );
 
         {
-            let envelope = Envelope::from(
-                inbox
-                    .receive(ShouldBlock::from(true))
-                    .expect("Cannot receive message"),
-            );
+            let envelope = inbox
+                .receive_envelope(ShouldBlock::from(true))
+                .expect("Cannot receive message");
+            // Both workers pass the headers on to the next stage
+            assert_eq!(envelope.header("tenant"), Some("acme"));
             let (_, _, message_bytes) = envelope.open();
 
-            let message: FirstMessageType =
+            let message: SecondMessageType =
                 bincode::deserialize(&message_bytes).expect("Spawner cannot deserialize envelope");
 
-            if let FirstMessageType::MessageB { c_foo, .. } = message {
+            if let SecondMessageType::MessageB { c_foo, .. } = message {
                 eprintln!("Spawner received message B: {}", &c_foo);
                 assert_eq!(c_foo, 50 + 42 + 43);
             } else {
@@ -627,7 +1163,7 @@ mod tests {
             }
         }
 
-        outbox.send_message(&FirstMessageType::MessageA);
+        client.send_message_a();
 
         {
             let envelope = Envelope::from(
@@ -637,11 +1173,11 @@ mod tests {
             );
             let (_, _, message_bytes) = envelope.open();
 
-            let message: FirstMessageType =
+            let message: SecondMessageType =
                 bincode::deserialize(&message_bytes).expect("Spawner cannot deserialize envelope");
             match message {
-                FirstMessageType::MessageA => {}
-                FirstMessageType::MessageB { .. } => {
+                SecondMessageType::MessageA => {}
+                SecondMessageType::MessageB { .. } => {
                     panic!("Spawner received wrong message type (expected B, received A)")
                 }
             }
@@ -659,26 +1195,29 @@ mod tests {
     fn run_derived_worker_remote() {
         let ctx = zmq::Context::new();
 
-        let first_worker_address = Address::new(AddressType::Remote);
+        // Let zmq pick the ports so that parallel test runs do not collide
+        let any_port: Address = "tcp://127.0.0.1:*".parse().unwrap();
+
+        let first_worker_inbox = Inbox::new(ctx.clone(), &any_port);
+        let first_worker_address = first_worker_inbox.address().clone();
         eprintln!("first_worker_address: {}", &first_worker_address);
 
-        let second_worker_address = Address::new(AddressType::Remote);
+        let second_worker_inbox = Inbox::bind_new(ctx.clone(), AddressType::Remote);
+        let second_worker_address = second_worker_inbox.address().clone();
         eprintln!("second_worker_address: {}", &second_worker_address);
 
-        let spawner_address = Address::new(AddressType::Remote);
+        let inbox = Inbox::new(ctx.clone(), &any_port);
+        let spawner_address = inbox.address().clone();
         eprintln!("spawner_address: {}", &spawner_address);
 
-        let inbox = Inbox::new(ctx.clone(), &spawner_address);
-
         let first_worker_thread = {
             let ctx_copy = ctx.clone();
-            let first_worker_address_copy = first_worker_address.clone();
             let second_worker_address_copy = second_worker_address.clone();
 
             std::thread::spawn(move || {
-                let mut first_worker = DerivedWorker::new(
+                let mut first_worker = DerivedWorker::with_inbox(
                     ctx_copy,
-                    &first_worker_address_copy,
+                    first_worker_inbox,
                     &second_worker_address_copy,
                     42,
                 );
@@ -689,13 +1228,12 @@ mod tests {
 
         let second_worker_thread = {
             let ctx_copy = ctx.clone();
-            let second_worker_address_copy = second_worker_address.clone();
             let spawner_address_copy = spawner_address.clone();
 
             std::thread::spawn(move || {
-                let mut second_worker = DerivedWorker::new(
+                let mut second_worker = DerivedWorker::with_inbox(
                     ctx_copy,
-                    &second_worker_address_copy,
+                    second_worker_inbox,
                     &spawner_address_copy,
                     43,
                 );
@@ -704,11 +1242,10 @@ mod tests {
             })
         };
 
-        let outbox = Outbox::new(ctx.clone(), &first_worker_address, &spawner_address);
-        outbox.send_message(&FirstMessageType::MessageB {
-            c_foo: 50,
-            c_bar: "Ta-da-da".to_owned(),
-        });
+        let client = SecondMessageTypeClient::from(
+            Outbox::new(ctx.clone(), &first_worker_address, &spawner_address).typed(),
+        );
+        client.send_message_b(50, "Ta-da-da".to_owned());
 
         {
             let envelope = Envelope::from(
@@ -730,10 +1267,10 @@ mod tests {
                 "peek() function returned different address than open()"
             );
 
-            let message: FirstMessageType =
+            let message: SecondMessageType =
                 bincode::deserialize(&message_bytes).expect("Spawner cannot deserialize envelope");
 
-            if let FirstMessageType::MessageB { c_foo, .. } = message {
+            if let SecondMessageType::MessageB { c_foo, .. } = message {
                 eprintln!("Spawner received message B: {}", &c_foo);
                 assert_eq!(c_foo, 50 + 42 + 43);
             } else {
@@ -741,7 +1278,7 @@ mod tests {
             }
         }
 
-        outbox.send_message(&FirstMessageType::MessageA);
+        client.send_message_a();
 
         {
             let envelope = Envelope::from(
@@ -751,11 +1288,11 @@ mod tests {
             );
             let (_, _, message_bytes) = envelope.open();
 
-            let message: FirstMessageType =
+            let message: SecondMessageType =
                 bincode::deserialize(&message_bytes).expect("Spawner cannot deserialize envelope");
             match message {
-                FirstMessageType::MessageA => {}
-                FirstMessageType::MessageB { .. } => {
+                SecondMessageType::MessageA => {}
+                SecondMessageType::MessageB { .. } => {
                     panic!("Spawner received wrong message type (expected B, received A)")
                 }
             }
@@ -768,4 +1305,681 @@ mod tests {
             .join()
             .expect("Cannot join second worker");
     }
+
+    #[test]
+    fn malformed_envelope_is_an_error() {
+        let envelope = Envelope::from(vec![1, 2, 3]);
+        assert!(matches!(
+            envelope.try_peek(),
+            Err(Error::MalformedEnvelope(_))
+        ));
+        assert!(matches!(
+            envelope.try_open(),
+            Err(Error::MalformedEnvelope(_))
+        ));
+
+        let envelope = Envelope::from(vec![0xff; 100]);
+        assert!(matches!(
+            envelope.try_open(),
+            Err(Error::MalformedEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn busy_address_is_an_error() {
+        let ctx = zmq::Context::new();
+        let address = Address::new(AddressType::Local);
+
+        let _inbox = Inbox::new(ctx.clone(), &address);
+        assert!(matches!(
+            Inbox::try_new(ctx, &address),
+            Err(Error::Transport(zmq::Error::EADDRINUSE))
+        ));
+    }
+
+    #[test]
+    fn parse_addresses() {
+        use std::convert::TryFrom;
+
+        let address: Address = "tcp://10.0.0.1:5555".parse().unwrap();
+        assert_eq!(address.get_type(), AddressType::Remote);
+        assert_eq!(address.to_string(), "tcp://10.0.0.1:5555");
+
+        let address = Address::try_from("ipc:///tmp/yocto.sock").unwrap();
+        assert_eq!(address.get_type(), AddressType::Ipc);
+
+        let address = Address::try_from("inproc://worker").unwrap();
+        assert_eq!(address.get_type(), AddressType::Local);
+
+        let address = Address::from("[::1]:5555".parse::<std::net::SocketAddr>().unwrap());
+        assert_eq!(address.get_type(), AddressType::Remote);
+
+        for malformed in &[
+            "",
+            "udp://10.0.0.1:5555",
+            "tcp://",
+            "tcp://10.0.0.1",
+            "tcp://10.0.0.1:http",
+            "tcp://10.0.0.1:65536",
+            "tcp://::1:5555",
+            "inproc://",
+        ] {
+            assert!(
+                matches!(Address::try_from(*malformed), Err(Error::AddressParse(_))),
+                "{:?} should not parse",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn run_over_ipc() {
+        let ctx = zmq::Context::new();
+
+        let inbox_address = Address::new(AddressType::Ipc);
+        assert_eq!(inbox_address.get_type(), AddressType::Ipc);
+
+        let inbox = Inbox::new(ctx.clone(), &inbox_address);
+        let outbox = Outbox::new(ctx, &inbox_address, &inbox_address);
+        outbox.send_message(&FirstMessageType::MessageA);
+
+        let envelope = Envelope::from(
+            inbox
+                .receive(ShouldBlock::from(true))
+                .expect("Cannot receive message"),
+        );
+        let (dest, _, message_bytes) = envelope.open();
+        assert_eq!(&dest, &inbox_address);
+
+        let message: FirstMessageType =
+            bincode::deserialize(&message_bytes).expect("Cannot deserialize envelope");
+        assert!(matches!(message, FirstMessageType::MessageA));
+    }
+
+    #[test]
+    fn long_addresses_survive_envelope() {
+        let dest_address: Address = "tcp://my-long-hostname.internal:5555".parse().unwrap();
+        let source_address = Address::from(std::net::SocketAddr::new(
+            "fd00:1234:5678:9abc:def0:1234:5678:9abc".parse().unwrap(),
+            65535,
+        ));
+
+        let envelope = Envelope::new(vec![1, 2, 3], &dest_address, &source_address);
+        let (dest, source, message_bytes) = envelope.open();
+
+        assert_eq!(&dest, &dest_address);
+        assert_eq!(&source, &source_address);
+        assert_eq!(message_bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn open_legacy_envelope() {
+        let pad = |conn_string: &str| {
+            let mut bytes = conn_string.as_bytes().to_vec();
+            bytes.resize(32, 0);
+            bytes
+        };
+
+        let mut legacy_bytes = vec![4, 5, 6];
+        legacy_bytes.extend(pad("tcp://127.0.0.1:5001"));
+        legacy_bytes.extend(pad("inproc://some-worker"));
+
+        let envelope = Envelope::from(legacy_bytes);
+        let (peeked_dest, peeked_source) = envelope.peek();
+        let (dest, source, message_bytes) = envelope.open();
+
+        assert_eq!(&peeked_dest, &dest);
+        assert_eq!(&peeked_source, &source);
+        assert_eq!(&dest, &"inproc://some-worker".parse::<Address>().unwrap());
+        assert_eq!(&source, &"tcp://127.0.0.1:5001".parse::<Address>().unwrap());
+        assert_eq!(message_bytes, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn bind_to_any_port() {
+        let ctx = zmq::Context::new();
+
+        let inbox = Inbox::new(ctx.clone(), &"tcp://127.0.0.1:*".parse().unwrap());
+        let address = inbox.address().clone();
+        assert!(!address.as_str().ends_with(":*"));
+        assert_eq!(address.get_type(), AddressType::Remote);
+
+        let outbox = Outbox::new(ctx.clone(), &address, &address);
+        outbox.send_message(&FirstMessageType::MessageA);
+        assert!(inbox.receive(ShouldBlock::from(true)).is_some());
+
+        // The resolved port is now taken, so binding to it again must fail
+        assert!(matches!(
+            Inbox::try_new(ctx, &address),
+            Err(Error::Transport(zmq::Error::EADDRINUSE))
+        ));
+    }
+
+    #[test]
+    fn ignore_stop_requests_of_other_generations() {
+        let ctx = zmq::Context::new();
+
+        let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local).with_generation(2);
+        let address = inbox.address().clone();
+        let outbox = Outbox::new(ctx.clone(), &address, &address);
+
+        // Meant for the generation before, e.g. one that was already gone
+        outbox.send_envelope(
+            Envelope::stop_request(&address, &address)
+                .try_with_stop_generation(1)
+                .unwrap(),
+        );
+        outbox.send_message(&FirstMessageType::MessageA);
+        assert!(inbox.receive_envelope(ShouldBlock::from(true)).is_some());
+        assert!(!inbox.stop_requested());
+
+        outbox.send_envelope(
+            Envelope::stop_request(&address, &address)
+                .try_with_stop_generation(2)
+                .unwrap(),
+        );
+        assert!(inbox.receive_envelope(ShouldBlock::from(true)).is_none());
+        assert!(inbox.stop_requested());
+
+        // Untagged stop requests stop any generation
+        let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local).with_generation(3);
+        Outbox::new(ctx, inbox.address(), &address)
+            .send_envelope(Envelope::stop_request(inbox.address(), &address));
+        assert!(inbox.receive_envelope(ShouldBlock::from(true)).is_none());
+        assert!(inbox.stop_requested());
+    }
+
+    #[test]
+    fn bind_new_remote_inboxes_to_free_ports() {
+        let ctx = zmq::Context::new();
+
+        let first = Inbox::bind_new(ctx.clone(), AddressType::Remote);
+        let second = Inbox::bind_new(ctx.clone(), AddressType::Remote);
+        assert!(!first.address().as_str().ends_with(":*"));
+        assert_ne!(first.address(), second.address());
+
+        let random = Inbox::bind_random(ctx, AddressType::Remote);
+        assert_eq!(random.address().get_type(), AddressType::Remote);
+    }
+
+    #[test]
+    fn forward_envelope() {
+        let ctx = zmq::Context::new();
+
+        let spawner_address = Address::new(AddressType::Local);
+        let forwarder_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let final_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        let outbox = Outbox::new(ctx.clone(), forwarder_inbox.address(), &spawner_address);
+        let mut headers = Headers::new();
+        headers.insert("tenant".to_owned(), "acme".to_owned());
+
+        // Big enough for zmq to keep it out of line
+        outbox.send_message_with_headers(
+            &FirstMessageType::MessageB {
+                c_foo: 1,
+                c_bar: "forwarded".repeat(100),
+            },
+            &headers,
+        );
+
+        let envelope = forwarder_inbox
+            .receive_envelope(ShouldBlock::from(true))
+            .expect("Cannot receive message");
+        let payload_address = envelope.payload().unwrap().as_ptr();
+
+        let forwarding_outbox = Outbox::new(
+            ctx.clone(),
+            final_inbox.address(),
+            forwarder_inbox.address(),
+        );
+        forwarding_outbox.send_envelope(envelope.with_header("hops", "1"));
+
+        let envelope = final_inbox
+            .receive_envelope(ShouldBlock::from(true))
+            .expect("Cannot receive message");
+        // inproc hands the very same buffer over instead of copying it
+        assert_eq!(envelope.payload().unwrap().as_ptr(), payload_address);
+        assert_eq!(envelope.header("tenant"), Some("acme"));
+        assert_eq!(envelope.header("hops"), Some("1"));
+
+        let (dest, source, message_bytes) = envelope.open();
+        assert_eq!(&dest, final_inbox.address());
+        assert_eq!(&source, &spawner_address);
+
+        let message: FirstMessageType =
+            bincode::deserialize(&message_bytes).expect("Cannot deserialize envelope");
+        assert!(matches!(
+            message,
+            FirstMessageType::MessageB { c_foo: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn forward_single_frame_envelope() {
+        let ctx = zmq::Context::new();
+
+        let spawner_address = Address::new(AddressType::Local);
+        let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        let envelope = Envelope::new(vec![1, 2, 3], &spawner_address, &spawner_address);
+        let outbox = Outbox::new(ctx, inbox.address(), &spawner_address);
+        outbox.send_envelope(Envelope::from(envelope.into_bytes()));
+
+        let (dest, source, message_bytes) = Envelope::from(
+            inbox
+                .receive(ShouldBlock::from(true))
+                .expect("Cannot receive message"),
+        )
+        .open();
+        assert_eq!(&dest, inbox.address());
+        assert_eq!(&source, &spawner_address);
+        assert_eq!(message_bytes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn receive_with_timeout() {
+        let ctx = zmq::Context::new();
+
+        let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let started = Instant::now();
+        assert!(inbox.receive_timeout(Duration::from_millis(50)).is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(matches!(
+            inbox.try_receive_envelope_timeout(Duration::from_millis(0)),
+            Err(Error::Timeout)
+        ));
+
+        let outbox = Outbox::new(ctx, inbox.address(), inbox.address());
+        outbox.send_message(&FirstMessageType::MessageA);
+        let envelope = inbox
+            .receive_envelope_timeout(Duration::from_secs(10))
+            .expect("Message did not arrive in time");
+
+        let message: FirstMessageType =
+            bincode::deserialize(envelope.payload().unwrap()).expect("Cannot deserialize envelope");
+        assert!(matches!(message, FirstMessageType::MessageA));
+    }
+
+    #[actor_message]
+    #[derive(Serialize, Deserialize)]
+    pub enum ThirdMessageType {
+        Stop,
+    }
+
+    struct IdleWorker {
+        inbox: Inbox,
+        idle_count: usize,
+    }
+
+    impl ThirdMessageTypeHandler for IdleWorker {
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
+
+        fn idle_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+
+        fn on_idle(&mut self) -> ShouldTerminate {
+            self.idle_count += 1;
+            ShouldTerminate::from(self.idle_count == 3)
+        }
+
+        fn handle_stop(&mut self) -> ShouldTerminate {
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[test]
+    fn run_idle_worker() {
+        let ctx = zmq::Context::new();
+
+        let mut worker = IdleWorker {
+            inbox: Inbox::bind_new(ctx, AddressType::Local),
+            idle_count: 0,
+        };
+        worker.run();
+        assert_eq!(worker.idle_count, 3);
+    }
+
+    struct StrictWorker {
+        inbox: Inbox,
+        dead_letters: Outbox,
+    }
+
+    impl ThirdMessageTypeHandler for StrictWorker {
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
+
+        fn dead_letters(&self) -> Option<&Outbox> {
+            Some(&self.dead_letters)
+        }
+
+        fn handle_stop(&mut self) -> ShouldTerminate {
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[test]
+    fn route_other_protocols_to_dead_letters() {
+        let ctx = zmq::Context::new();
+        let sink = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        let mut worker = StrictWorker {
+            inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+            dead_letters: Outbox::new(ctx.clone(), sink.address(), sink.address()),
+        };
+        let outbox = Outbox::new(ctx, worker.inbox.address(), sink.address());
+
+        // Both encode as variant 0, only the type tag tells them apart
+        outbox.send_message(&Greeting::Hello {
+            name: "Stranger".to_owned(),
+        });
+        outbox.send_message(&ThirdMessageType::Stop);
+        worker.run();
+
+        let envelope = sink
+            .receive_envelope_timeout(Duration::from_secs(10))
+            .expect("Dead letter did not arrive");
+        assert!(matches!(
+            envelope.try_check_type_tag::<ThirdMessageType>(),
+            Err(Error::ProtocolMismatch { .. })
+        ));
+        assert!(matches!(
+            envelope.decode(),
+            Greeting::Hello { name } if name == "Stranger"
+        ));
+        assert!(sink.receive(ShouldBlock::from(false)).is_none());
+    }
+
+    // Keeps the mismatches instead of passing them on
+    struct MismatchCounter {
+        inbox: Inbox,
+        mismatches: Cell<usize>,
+    }
+
+    impl ThirdMessageTypeHandler for MismatchCounter {
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
+
+        fn on_protocol_mismatch(&self, err: Error, _envelope: Envelope) {
+            assert!(matches!(err, Error::ProtocolMismatch { .. }));
+            self.mismatches.set(self.mismatches.get() + 1);
+        }
+
+        fn handle_stop(&mut self) -> ShouldTerminate {
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[test]
+    fn report_protocol_mismatches() {
+        let ctx = zmq::Context::new();
+
+        let mut worker = MismatchCounter {
+            inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+            mismatches: Cell::new(0),
+        };
+        let outbox = Outbox::new(ctx, worker.inbox.address(), worker.inbox.address());
+
+        outbox.send_message(&Greeting::Goodbye);
+        outbox.send_message(&Greeting::Goodbye);
+        outbox.send_message(&ThirdMessageType::Stop);
+        worker.run();
+
+        assert_eq!(worker.mismatches.get(), 2);
+    }
+
+    #[test]
+    fn survive_bad_envelopes() {
+        let ctx = zmq::Context::new();
+        let sink = Inbox::bind_new(ctx.clone(), AddressType::Local);
+
+        let mut worker = StrictWorker {
+            inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+            dead_letters: Outbox::new(ctx.clone(), sink.address(), sink.address()),
+        };
+        let outbox = Outbox::new(ctx.clone(), worker.inbox.address(), sink.address());
+
+        // Not an envelope at all, so there is nothing to pass on
+        let raw_socket = ctx.socket(zmq::PUSH).unwrap();
+        raw_socket.connect(worker.inbox.address().as_str()).unwrap();
+        raw_socket
+            .send_multipart([&b"one"[..], &b"two"[..], &b"three"[..]], 0)
+            .unwrap();
+
+        // A proper envelope whose payload does not decode
+        outbox.send_envelope(Envelope::new(
+            vec![0xff; 3],
+            worker.inbox.address(),
+            sink.address(),
+        ));
+        outbox.send_message(&ThirdMessageType::Stop);
+        worker.run();
+
+        let envelope = sink
+            .receive_envelope_timeout(Duration::from_secs(10))
+            .expect("Dead letter did not arrive");
+        assert_eq!(envelope.payload().unwrap(), &[0xff; 3][..]);
+        assert!(sink.receive(ShouldBlock::from(false)).is_none());
+    }
+
+    #[actor_message(context)]
+    #[derive(Serialize, Deserialize)]
+    pub enum Greeting {
+        Hello { name: String },
+        Goodbye,
+    }
+
+    struct Greeter {
+        inbox: Inbox,
+    }
+
+    impl GreetingHandler for Greeter {
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
+
+        fn handle_hello(&mut self, ctx: &Context, name: String) -> ShouldTerminate {
+            let greeting = match ctx.header("language") {
+                Some("de") => format!("Hallo, {}", name),
+                _ => format!("Hello, {}", name),
+            };
+            ctx.reply(&Greeting::Hello { name: greeting });
+            ShouldTerminate::from(false)
+        }
+
+        fn handle_goodbye(&mut self, ctx: &Context) -> ShouldTerminate {
+            assert_eq!(ctx.dest_address(), self.inbox.address());
+            ctx.reply_outbox().send_message(&Greeting::Goodbye);
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[test]
+    fn handlers_with_context() {
+        let ctx = zmq::Context::new();
+
+        let mut greeter = Greeter {
+            inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        };
+        let greeter_address = greeter.inbox.address().clone();
+        let greeter_thread = std::thread::spawn(move || greeter.run());
+
+        let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let outbox = Outbox::new(ctx, &greeter_address, inbox.address());
+
+        let reply: Greeting = outbox.ask(
+            &Greeting::Hello {
+                name: "Ada".to_owned(),
+            },
+            Duration::from_secs(10),
+        );
+        assert!(matches!(reply, Greeting::Hello { name } if name == "Hello, Ada"));
+
+        let mut headers = Headers::new();
+        headers.insert("language".to_owned(), "de".to_owned());
+        outbox.send_message_with_headers(
+            &Greeting::Hello {
+                name: "Ada".to_owned(),
+            },
+            &headers,
+        );
+        let envelope = inbox
+            .receive_envelope(ShouldBlock::from(true))
+            .expect("Cannot receive message");
+        let (_, source) = envelope.peek();
+        assert_eq!(&source, &greeter_address);
+        assert!(matches!(envelope.decode(), Greeting::Hello { name } if name == "Hallo, Ada"));
+
+        outbox.send_message(&Greeting::Goodbye);
+        assert!(matches!(
+            inbox
+                .receive_envelope(ShouldBlock::from(true))
+                .unwrap()
+                .decode(),
+            Greeting::Goodbye
+        ));
+
+        greeter_thread.join().expect("Cannot join greeter");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn reply_in_the_codec_of_the_request() {
+        use crate::{Codec, Json};
+
+        let ctx = zmq::Context::new();
+
+        let mut greeter = Greeter {
+            inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        };
+        let greeter_address = greeter.inbox.address().clone();
+        let greeter_thread = std::thread::spawn(move || greeter.run());
+
+        let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let outbox = Outbox::new(ctx, &greeter_address, inbox.address()).with_codec::<Json>();
+
+        outbox.send_message(&Greeting::Hello {
+            name: "Ada".to_owned(),
+        });
+        let envelope = inbox
+            .receive_envelope(ShouldBlock::from(true))
+            .expect("Cannot receive message");
+        assert_eq!(envelope.codec_id(), Json::ID);
+        assert_eq!(
+            envelope.payload().unwrap(),
+            br#"{"Hello":{"name":"Hello, Ada"}}"#
+        );
+
+        outbox.send_message(&Greeting::Goodbye);
+        let envelope = inbox
+            .receive_envelope(ShouldBlock::from(true))
+            .expect("Cannot receive message");
+        assert_eq!(envelope.codec_id(), Json::ID);
+        assert!(matches!(envelope.decode(), Greeting::Goodbye));
+
+        greeter_thread.join().expect("Cannot join greeter");
+    }
+
+    #[test]
+    fn ask_and_reply() {
+        let ctx = zmq::Context::new();
+
+        let worker_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let outbox = Outbox::new(
+            ctx.clone(),
+            worker_inbox.address(),
+            &Address::new(AddressType::Local),
+        );
+
+        let worker_thread = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let envelope = worker_inbox
+                    .receive_envelope(ShouldBlock::from(true))
+                    .expect("Cannot receive message");
+                let message: FirstMessageType = bincode::deserialize(envelope.payload().unwrap())
+                    .expect("Worker cannot deserialize envelope");
+
+                if let FirstMessageType::MessageB { c_foo, c_bar } = message {
+                    worker_inbox.reply(
+                        &envelope,
+                        &FirstMessageType::MessageB {
+                            c_foo: c_foo + 1,
+                            c_bar,
+                        },
+                    );
+                }
+            }
+        });
+
+        let reply: FirstMessageType = outbox.ask(
+            &FirstMessageType::MessageB {
+                c_foo: 1,
+                c_bar: "question".to_owned(),
+            },
+            Duration::from_secs(10),
+        );
+        assert!(matches!(reply, FirstMessageType::MessageB { c_foo: 2, .. }));
+
+        // The worker ignores MessageA, so nothing ever comes back
+        let reply_inbox = Inbox::bind_new(ctx, AddressType::Local);
+        let started = Instant::now();
+        assert!(matches!(
+            outbox.try_ask_via::<_, FirstMessageType>(
+                &FirstMessageType::MessageA,
+                &reply_inbox,
+                Duration::from_millis(100),
+            ),
+            Err(Error::Timeout)
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        worker_thread.join().expect("Cannot join worker");
+    }
+
+    #[test]
+    fn ask_rejects_replies_of_another_type() {
+        let ctx = zmq::Context::new();
+
+        let worker_inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+        let outbox = Outbox::new(
+            ctx.clone(),
+            worker_inbox.address(),
+            &Address::new(AddressType::Local),
+        );
+
+        let worker_thread = std::thread::spawn(move || {
+            let envelope = worker_inbox
+                .receive_envelope(ShouldBlock::from(true))
+                .expect("Cannot receive message");
+            worker_inbox.reply(&envelope, &Greeting::Goodbye);
+        });
+
+        assert!(matches!(
+            outbox.try_ask::<_, SecondMessageType>(
+                &SecondMessageType::MessageA,
+                Duration::from_secs(10),
+            ),
+            Err(Error::ProtocolMismatch { .. })
+        ));
+
+        worker_thread.join().expect("Cannot join worker");
+    }
+
+    #[test]
+    fn outbox_linger() {
+        let ctx = zmq::Context::new();
+        let address = Address::new(AddressType::Local);
+
+        let outbox = Outbox::new(ctx, &address, &address);
+        assert_eq!(outbox.linger(), None);
+        let outbox = outbox.with_linger(Some(Duration::from_millis(250)));
+        assert_eq!(outbox.linger(), Some(Duration::from_millis(250)));
+        let outbox = outbox.with_linger(None);
+        assert_eq!(outbox.linger(), None);
+    }
 }
@@ -0,0 +1,513 @@
+// Runs many actors on a fixed number of threads. A poller thread waits on the
+// inboxes of the actors that have nothing to do and hands those that received
+// something to the workers. An actor is only ever on one thread at a time, so
+// its handlers still run one after another.
+
+use crate::{ActorRef, Address, AddressType, Error, Inbox, Result, ShouldTerminate};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+// How many messages a worker handles for one actor before moving on to the
+// next, unless told otherwise through Scheduler::with_throughput. Draining
+// actors empty their inbox in one go, see run_turn.
+const DEFAULT_THROUGHPUT: usize = 16;
+
+type Panic = Box<dyn Any + Send>;
+
+// Implemented by the actors a Scheduler runs. Actors generated by
+// #[actor_message] only need to forward to their handler trait, e.g.
+// ```
+// impl Scheduled for Worker {
+//     fn inbox(&self) -> &Inbox {
+//         &self.inbox
+//     }
+//
+//     fn start(&mut self) {
+//         self.pre_start();
+//     }
+//
+//     fn turn(&mut self) -> Option<ShouldTerminate> {
+//         self.run_turn()
+//     }
+// }
+// ```
+pub trait Scheduled: Send {
+    // What the scheduler waits on for messages
+    fn inbox(&self) -> &Inbox;
+
+    // Called before the first turn
+    fn start(&mut self) {}
+
+    // Handles the next queued message without waiting for one. Returns None
+    // if nothing was queued, otherwise whether the actor is done.
+    fn turn(&mut self) -> Option<ShouldTerminate>;
+}
+
+struct Task {
+    actor: Box<dyn Scheduled>,
+    address: Address,
+    started: bool,
+}
+
+// What the poller learns from the other threads
+enum Event {
+    Spawned(Task, ActorRef),
+    // Back from a worker, waiting for messages again
+    Returned(Task),
+    Done(Address),
+    Panicked(Address, Panic),
+    Shutdown { drain: bool },
+}
+
+pub struct Scheduler {
+    zmq_ctx: zmq::Context,
+    throughput: Arc<AtomicUsize>,
+    events: Sender<Event>,
+    // Wakes the poller up after sending it an event
+    wake: zmq::Socket,
+    poller: Option<JoinHandle<Result<Option<Panic>>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(zmq_ctx: zmq::Context, worker_count: usize) -> Self {
+        Self::try_new(zmq_ctx, worker_count).expect("Cannot start scheduler")
+    }
+
+    pub fn try_new(zmq_ctx: zmq::Context, worker_count: usize) -> Result<Self> {
+        let throughput = Arc::new(AtomicUsize::new(DEFAULT_THROUGHPUT));
+        let (events, poller_events) = mpsc::channel();
+        let (tasks, worker_tasks) = mpsc::channel();
+        let worker_tasks = Arc::new(Mutex::new(worker_tasks));
+
+        let wake_inbox = Inbox::try_bind_new(zmq_ctx.clone(), AddressType::Local)?;
+        let wake = wake_socket(&zmq_ctx, wake_inbox.address())?;
+
+        let mut workers = Vec::with_capacity(worker_count);
+        for index in 0..worker_count {
+            let worker = Worker {
+                tasks: Arc::clone(&worker_tasks),
+                events: events.clone(),
+                wake: wake_socket(&zmq_ctx, wake_inbox.address())?,
+                throughput: Arc::clone(&throughput),
+            };
+            workers.push(
+                std::thread::Builder::new()
+                    .name(format!("scheduler worker {}", index))
+                    .spawn(move || worker.run())?,
+            );
+        }
+
+        let poller = std::thread::Builder::new()
+            .name("scheduler poller".to_owned())
+            .spawn(move || poll(wake_inbox, poller_events, tasks))?;
+
+        Ok(Self {
+            zmq_ctx,
+            throughput,
+            events,
+            wake,
+            poller: Some(poller),
+            workers,
+        })
+    }
+
+    // Fewer messages per turn share the workers more fairly between busy
+    // actors, more save trips through the poller
+    pub fn with_throughput(self, messages_per_turn: usize) -> Self {
+        self.throughput
+            .store(messages_per_turn.max(1), Ordering::Relaxed);
+        self
+    }
+
+    pub fn context(&self) -> &zmq::Context {
+        &self.zmq_ctx
+    }
+
+    // Binds a fresh local inbox and schedules the actor `make` builds around
+    // it, e.g. `scheduler.spawn(|inbox| Worker { inbox })`
+    pub fn spawn<A, F>(&mut self, make: F) -> ActorRef
+    where
+        A: Scheduled + 'static,
+        F: FnOnce(Inbox) -> A,
+    {
+        self.try_spawn(make).expect("Cannot spawn actor")
+    }
+
+    pub fn try_spawn<A, F>(&mut self, make: F) -> Result<ActorRef>
+    where
+        A: Scheduled + 'static,
+        F: FnOnce(Inbox) -> A,
+    {
+        self.try_spawn_on(AddressType::Local, make)
+    }
+
+    // Like spawn, with an inbox reachable from other processes or machines
+    pub fn spawn_on<A, F>(&mut self, address_type: AddressType, make: F) -> ActorRef
+    where
+        A: Scheduled + 'static,
+        F: FnOnce(Inbox) -> A,
+    {
+        self.try_spawn_on(address_type, make)
+            .expect("Cannot spawn actor")
+    }
+
+    pub fn try_spawn_on<A, F>(&mut self, address_type: AddressType, make: F) -> Result<ActorRef>
+    where
+        A: Scheduled + 'static,
+        F: FnOnce(Inbox) -> A,
+    {
+        let inbox = Inbox::try_bind_new(self.zmq_ctx.clone(), address_type)?;
+        let address = inbox.address().clone();
+        let actor_ref = ActorRef::new(self.zmq_ctx.clone(), address.clone());
+
+        let task = Task {
+            actor: Box::new(make(inbox)),
+            address,
+            started: false,
+        };
+        self.send_event(Event::Spawned(task, actor_ref.clone()))?;
+        Ok(actor_ref)
+    }
+
+    // Asks every actor to stop and waits until all of them did. Messages
+    // still queued when the stop request arrives may or may not be handled.
+    // Panics if an actor did.
+    pub fn shutdown(mut self) {
+        self.try_shutdown().expect("Cannot shut down scheduler");
+    }
+
+    pub fn try_shutdown(&mut self) -> Result<()> {
+        self.try_stop_all(false)
+    }
+
+    // Like shutdown, but each actor first handles the messages that were
+    // queued in its inbox when the stop request arrived
+    pub fn drain(mut self) {
+        self.try_drain().expect("Cannot shut down scheduler");
+    }
+
+    pub fn try_drain(&mut self) -> Result<()> {
+        self.try_stop_all(true)
+    }
+
+    fn try_stop_all(&mut self, drain: bool) -> Result<()> {
+        let poller = match self.poller.take() {
+            Some(poller) => poller,
+            None => return Ok(()),
+        };
+
+        let sent = self.send_event(Event::Shutdown { drain });
+        // Unless the poller failed, it would wait for the shutdown forever
+        if sent.is_err() && !poller.is_finished() {
+            return sent;
+        }
+        let polled = match poller.join() {
+            Ok(polled) => polled,
+            Err(panic) => panic::resume_unwind(panic),
+        };
+        // Workers leave once the poller is gone
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+
+        if let Some(panic) = polled? {
+            panic::resume_unwind(panic);
+        }
+        sent
+    }
+
+    fn send_event(&self, event: Event) -> Result<()> {
+        // The poller only leaves on shutdown or failure
+        self.events.send(event).map_err(|_| Error::Stopped)?;
+        self.wake.send(zmq::Message::new(), 0)?;
+        Ok(())
+    }
+}
+
+// Dropping the scheduler shuts the actors down as well, but does not tell
+// about actors that panicked
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        if let Some(poller) = self.poller.take() {
+            if self.send_event(Event::Shutdown { drain: false }).is_ok() {
+                let _ = poller.join();
+                for worker in self.workers.drain(..) {
+                    let _ = worker.join();
+                }
+            }
+        }
+    }
+}
+
+fn wake_socket(zmq_ctx: &zmq::Context, address: &Address) -> Result<zmq::Socket> {
+    let socket = zmq_ctx.socket(zmq::PUSH)?;
+    socket.connect(address.as_str())?;
+    Ok(socket)
+}
+
+// Waits for messages to the idle actors and events from the other threads
+// until asked to shut down and every actor is done. Returns the first panic
+// of an actor, if any.
+fn poll(wake: Inbox, events: Receiver<Event>, tasks: Sender<Task>) -> Result<Option<Panic>> {
+    let mut idle: Vec<Task> = Vec::new();
+    let mut running: Vec<ActorRef> = Vec::new();
+    let mut stopping = false;
+    let mut first_panic = None;
+
+    loop {
+        while let Ok(event) = events.try_recv() {
+            match event {
+                Event::Spawned(task, actor_ref) => {
+                    if stopping {
+                        actor_ref.try_request_stop(false)?;
+                    }
+                    running.push(actor_ref);
+                    idle.push(task);
+                }
+                Event::Returned(task) => idle.push(task),
+                Event::Done(address) => running.retain(|actor_ref| actor_ref.address() != &address),
+                Event::Panicked(address, panic) => {
+                    running.retain(|actor_ref| actor_ref.address() != &address);
+                    first_panic.get_or_insert(panic);
+                }
+                Event::Shutdown { drain } => {
+                    stopping = true;
+                    // Actors that are done closed their inbox already, there
+                    // is no point in asking them
+                    for actor_ref in running.iter().rev() {
+                        actor_ref.try_request_stop(drain)?;
+                    }
+                }
+            }
+        }
+
+        if stopping && running.is_empty() {
+            return Ok(first_panic);
+        }
+
+        let mut items: Vec<_> = std::iter::once(wake.control_socket.as_poll_item(zmq::POLLIN))
+            .chain(
+                idle.iter()
+                    .map(|task| task.actor.inbox().control_socket.as_poll_item(zmq::POLLIN)),
+            )
+            .collect();
+        match zmq::poll(&mut items, -1) {
+            Ok(_) | Err(zmq::Error::EINTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let woken = items[0].is_readable();
+        let ready: Vec<usize> = items[1..]
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.is_readable())
+            .map(|(index, _)| index)
+            .collect();
+
+        // Highest index first, so that swap_remove does not move a ready task
+        for index in ready.into_iter().rev() {
+            // Workers only leave once we are gone
+            let _ = tasks.send(idle.swap_remove(index));
+        }
+        if woken {
+            while wake.control_socket.recv_bytes(zmq::DONTWAIT).is_ok() {}
+        }
+    }
+}
+
+struct Worker {
+    tasks: Arc<Mutex<Receiver<Task>>>,
+    events: Sender<Event>,
+    wake: zmq::Socket,
+    throughput: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    fn run(self) {
+        loop {
+            // Held while waiting, the other workers wait on the lock instead
+            let task = match self.tasks.lock() {
+                Ok(tasks) => tasks.recv(),
+                Err(_) => return,
+            };
+            let mut task = match task {
+                Ok(task) => task,
+                Err(_) => return,
+            };
+
+            let throughput = self.throughput.load(Ordering::Relaxed);
+            // An actor that panics is not scheduled again, so no later turn
+            // sees what it left half-done
+            let turns = panic::catch_unwind(AssertUnwindSafe(|| {
+                if !task.started {
+                    task.started = true;
+                    task.actor.start();
+                }
+                run_turns(task.actor.as_mut(), throughput)
+            }));
+
+            let event = match turns {
+                Ok(true) => Event::Done(task.address),
+                Ok(false) => Event::Returned(task),
+                Err(panic) => Event::Panicked(task.address, panic),
+            };
+            if self.events.send(event).is_err() || self.wake.send(zmq::Message::new(), 0).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Returns whether the actor is done
+fn run_turns(actor: &mut dyn Scheduled, throughput: usize) -> bool {
+    for _ in 0..throughput {
+        match actor.turn() {
+            Some(should_terminate) => {
+                if should_terminate.into() {
+                    return true;
+                }
+            }
+            None => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use crate::test_actors::{total, Count, Counter, Stats};
+    use crate::{AddressType, Inbox, Outbox};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn scheduled_actors_share_workers() {
+        const ACTOR_COUNT: u64 = 100;
+        const WORKER_COUNT: usize = 4;
+
+        let mut scheduler = Scheduler::new(zmq::Context::new(), WORKER_COUNT).with_throughput(2);
+        let stats = Arc::new(Stats::default());
+
+        let inbox = Inbox::bind_new(scheduler.context().clone(), AddressType::Local);
+        let outboxes: Vec<Outbox> = (0..ACTOR_COUNT)
+            .map(|_| {
+                scheduler
+                    .spawn(|inbox| Counter::new(inbox, &stats))
+                    .outbox(inbox.address())
+            })
+            .collect();
+
+        for (i, outbox) in outboxes.iter().enumerate() {
+            for _ in 0..=i {
+                outbox.send_message(&Count::Add(1));
+            }
+        }
+        for (i, outbox) in outboxes.iter().enumerate() {
+            assert_eq!(total(outbox), i as u64 + 1);
+        }
+
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn busy_actors_leave_room_for_quiet_ones() {
+        const FLOOD: u64 = 100;
+
+        // A single worker, which the busy counter would keep for a second
+        // if it handled its whole inbox in one go
+        let mut scheduler = Scheduler::new(zmq::Context::new(), 1).with_throughput(1);
+        let busy_stats = Arc::new(Stats::default());
+        let quiet_stats = Arc::new(Stats::default());
+
+        let inbox = Inbox::bind_new(scheduler.context().clone(), AddressType::Local);
+        let busy = scheduler
+            .spawn(|inbox| Counter::new(inbox, &busy_stats).with_delay(Duration::from_millis(10)))
+            .outbox(inbox.address());
+        let quiet = scheduler
+            .spawn(|inbox| Counter::new(inbox, &quiet_stats))
+            .outbox(inbox.address());
+
+        for _ in 0..FLOOD {
+            busy.send_message(&Count::Add(1));
+        }
+        // Make sure the busy counter got going before the quiet one is asked
+        while busy_stats.added.load(Ordering::SeqCst) == 0 {
+            std::thread::yield_now();
+        }
+        quiet.send_message(&Count::Add(1));
+        assert_eq!(total(&quiet), 1);
+        assert!(busy_stats.added.load(Ordering::SeqCst) < FLOOD);
+
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn start_and_stop_hooks_run_once() {
+        const ACTOR_COUNT: usize = 20;
+
+        let mut scheduler = Scheduler::new(zmq::Context::new(), 3).with_throughput(1);
+        let stats = Arc::new(Stats::default());
+
+        let inbox = Inbox::bind_new(scheduler.context().clone(), AddressType::Local);
+        for i in 0..ACTOR_COUNT {
+            let outbox = scheduler
+                .spawn(|inbox| Counter::new(inbox, &stats))
+                .outbox(inbox.address());
+            // Some are idle, some busy when the stop request arrives
+            for _ in 0..i % 3 {
+                outbox.send_message(&Count::Add(1));
+            }
+        }
+
+        scheduler.shutdown();
+        assert_eq!(stats.started.load(Ordering::SeqCst), ACTOR_COUNT);
+        assert_eq!(stats.stopped.lock().unwrap().len(), ACTOR_COUNT);
+    }
+
+    #[test]
+    fn drained_actors_empty_their_inbox() {
+        let mut scheduler = Scheduler::new(zmq::Context::new(), 2);
+        let stats = Arc::new(Stats::default());
+
+        let inbox = Inbox::bind_new(scheduler.context().clone(), AddressType::Local);
+        let outbox = scheduler
+            .spawn(|inbox| Counter::new(inbox, &stats))
+            .outbox(inbox.address());
+        for i in 1..=100 {
+            outbox.send_message(&Count::Add(i));
+        }
+
+        scheduler.drain();
+        assert_eq!(stats.added.load(Ordering::SeqCst), 5050);
+    }
+
+    #[test]
+    #[should_panic(expected = "Asked to crash")]
+    fn drain_reports_panics() {
+        let mut scheduler = Scheduler::new(zmq::Context::new(), 2);
+        let stats = Arc::new(Stats::default());
+
+        let inbox = Inbox::bind_new(scheduler.context().clone(), AddressType::Local);
+        let crashing = scheduler
+            .spawn(|inbox| Counter::new(inbox, &stats))
+            .outbox(inbox.address());
+        let healthy = scheduler
+            .spawn(|inbox| Counter::new(inbox, &stats))
+            .outbox(inbox.address());
+
+        crashing.send_message(&Count::Crash);
+        // The other actor keeps going
+        healthy.send_message(&Count::Add(1));
+        assert_eq!(total(&healthy), 1);
+
+        // Drained, so that Crash is handled even if the stop request overtakes it
+        scheduler.drain();
+    }
+}
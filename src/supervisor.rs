@@ -0,0 +1,596 @@
+// Restarts child actors that panicked or returned an error. Children keep
+// their address across restarts, so outboxes pointing at them keep working.
+// That is why children cannot be Local, zmq only reconnects tcp and ipc peers.
+// Messages already taken in by the failed inbox are lost with it though.
+
+use crate::{
+    Actor, ActorRef, Address, AddressType, Error, Inbox, Message, Outbox, Result, ShouldBlock,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How often a restarted child tries to bind its old address, which only
+// frees up once zmq has closed the previous socket in the background
+const MAX_REBIND_ATTEMPTS: usize = 100;
+const REBIND_INTERVAL: Duration = Duration::from_millis(1);
+
+// Which children are restarted when one of them fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestartStrategy {
+    // Only the failed child
+    OneForOne,
+    // Every child, for children that cannot work without each other
+    OneForAll,
+    // The failed child and the ones added after it, which may depend on it
+    RestForOne,
+}
+
+// What a child returns from its run closure. Returning an error counts as a
+// failure, just like panicking does. Children that return () or Ok(()) are
+// done and not restarted, unless a failed sibling takes them along.
+// Actors generated with #[actor_message(error = ...)] report the handler
+// error their on_error stopped at through try_run, e.g.
+// `|inbox| Worker { inbox }.try_run()`, while run keeps it to itself.
+pub trait ChildExit {
+    fn failed(&self) -> bool;
+}
+
+impl ChildExit for () {
+    fn failed(&self) -> bool {
+        false
+    }
+}
+
+impl<E> ChildExit for std::result::Result<(), E> {
+    fn failed(&self) -> bool {
+        self.is_err()
+    }
+}
+
+// Sent to the supervisor inbox by the thread of a child that left its run closure
+#[derive(Serialize, Deserialize)]
+struct ChildExited {
+    child: usize,
+    generation: u64,
+    failed: bool,
+}
+
+impl Message for ChildExited {}
+
+struct Child {
+    actor_ref: ActorRef,
+    run: Arc<dyn Fn(Inbox) -> bool + Send + Sync>,
+    // Bound by add_child and taken by the first start, restarts bind anew
+    inbox: Option<Inbox>,
+    // None while the child is not running
+    thread: Option<JoinHandle<()>>,
+    // Tells the exit of the current thread from those of earlier ones
+    generation: u64,
+    restart_at: Option<Instant>,
+}
+
+pub struct Supervisor {
+    zmq_ctx: zmq::Context,
+    strategy: RestartStrategy,
+    max_restarts: usize,
+    within: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    children: Vec<Child>,
+    // When the restarts still inside the intensity window happened
+    restarts: VecDeque<Instant>,
+}
+
+impl Supervisor {
+    // Gives up after more than 3 restarts within 5 seconds and waits between
+    // 10 milliseconds and a second before restarting
+    pub fn new(zmq_ctx: zmq::Context, strategy: RestartStrategy) -> Self {
+        Self {
+            zmq_ctx,
+            strategy,
+            max_restarts: 3,
+            within: Duration::from_secs(5),
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            children: Vec::new(),
+            restarts: VecDeque::new(),
+        }
+    }
+
+    // run fails with Error::TooManyRestarts once children failed more than
+    // max_restarts times within the given time
+    pub fn with_restart_intensity(mut self, max_restarts: usize, within: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.within = within;
+        self
+    }
+
+    // The first restart waits min, every further one within the intensity
+    // window twice as long as the one before, up to max
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self
+    }
+
+    // Binds the child inbox right away, the child itself starts with run.
+    // `run` is called again on every restart, with an inbox bound to the same
+    // address, e.g. `supervisor.add_child(AddressType::Ipc, |inbox| Worker { inbox }.run())`.
+    pub fn add_child<F, R>(&mut self, address_type: AddressType, run: F) -> ActorRef
+    where
+        F: Fn(Inbox) -> R + Send + Sync + 'static,
+        R: ChildExit,
+    {
+        self.try_add_child(address_type, run)
+            .expect("Cannot add child actor")
+    }
+
+    pub fn try_add_child<F, R>(&mut self, address_type: AddressType, run: F) -> Result<ActorRef>
+    where
+        F: Fn(Inbox) -> R + Send + Sync + 'static,
+        R: ChildExit,
+    {
+        check_restartable(address_type)?;
+        let inbox = Inbox::try_bind_new(self.zmq_ctx.clone(), address_type)?;
+        Ok(self.push_child(inbox, run))
+    }
+
+    // Like add_child, for children that must be found at a well-known
+    // address, e.g. the children of a supervisor which is restarted itself
+    pub fn add_child_at<F, R>(&mut self, address: &Address, run: F) -> ActorRef
+    where
+        F: Fn(Inbox) -> R + Send + Sync + 'static,
+        R: ChildExit,
+    {
+        self.try_add_child_at(address, run)
+            .expect("Cannot add child actor")
+    }
+
+    pub fn try_add_child_at<F, R>(&mut self, address: &Address, run: F) -> Result<ActorRef>
+    where
+        F: Fn(Inbox) -> R + Send + Sync + 'static,
+        R: ChildExit,
+    {
+        check_restartable(address.try_get_type()?)?;
+        let inbox = rebind(&self.zmq_ctx, address)?;
+        Ok(self.push_child(inbox, run))
+    }
+
+    fn push_child<F, R>(&mut self, inbox: Inbox, run: F) -> ActorRef
+    where
+        F: Fn(Inbox) -> R + Send + Sync + 'static,
+        R: ChildExit,
+    {
+        let actor_ref = ActorRef::new(self.zmq_ctx.clone(), inbox.address().clone());
+
+        self.children.push(Child {
+            actor_ref: actor_ref.clone(),
+            run: Arc::new(move |inbox| run(inbox).failed()),
+            inbox: Some(inbox),
+            thread: None,
+            generation: 0,
+            restart_at: None,
+        });
+        actor_ref
+    }
+
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    // Starts the children in the order they were added and restarts them
+    // until a stop request arrives at inbox, which is also where children
+    // report their exits. Runs as an actor itself, e.g.
+    // `system.spawn(supervisor)`.
+    // Panics if the children failed too often, which lets a supervisor
+    // further up the tree restart this one.
+    pub fn run(&mut self, inbox: &Inbox) {
+        self.try_run(inbox).expect("Supervisor gave up");
+    }
+
+    // The children are stopped, the last added first, before this returns,
+    // whether it succeeds or not. Stopping relies on them leaving their run
+    // loop on stop requests, as the ones generated by #[actor_message] do.
+    // A drain request is passed on to them as such.
+    pub fn try_run(&mut self, inbox: &Inbox) -> Result<()> {
+        let supervised = self.supervise(inbox);
+
+        let drain = inbox.drain_requested();
+        let mut stopped = Ok(());
+        for index in (0..self.children.len()).rev() {
+            let child_stopped = self.stop_child(index, drain);
+            if stopped.is_ok() {
+                stopped = child_stopped;
+            }
+        }
+
+        supervised.and(stopped)
+    }
+
+    fn supervise(&mut self, inbox: &Inbox) -> Result<()> {
+        for index in 0..self.children.len() {
+            self.start_child(index, inbox.address())?;
+        }
+
+        loop {
+            let next_restart = self
+                .children
+                .iter()
+                .filter_map(|child| child.restart_at)
+                .min();
+            let received = match next_restart {
+                Some(restart_at) => match inbox.try_receive_envelope_timeout(
+                    restart_at.saturating_duration_since(Instant::now()),
+                ) {
+                    Ok(envelope) => Some(envelope),
+                    Err(Error::Timeout) => None,
+                    Err(Error::Stopped) => return Ok(()),
+                    Err(err) => return Err(err),
+                },
+                None => inbox.try_receive_envelope(ShouldBlock::from(true))?,
+            };
+
+            if inbox.stop_requested() {
+                return Ok(());
+            }
+
+            match received {
+                // Anything else sent here is ignored
+                Some(envelope) => {
+                    if let Ok(exited) = envelope.try_decode::<ChildExited>() {
+                        self.on_child_exited(exited)?;
+                    }
+                }
+                None => self.restart_due(inbox.address())?,
+            }
+        }
+    }
+
+    fn on_child_exited(&mut self, exited: ChildExited) -> Result<()> {
+        let child = match self.children.get_mut(exited.child) {
+            Some(child) if child.generation == exited.generation => child,
+            _ => return Ok(()),
+        };
+        // Children we stopped ourselves have been joined already
+        let thread = match child.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        // The panic hook has already reported a panic
+        let _ = thread.join();
+
+        if !exited.failed {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        while let Some(&restart) = self.restarts.front() {
+            if now.duration_since(restart) <= self.within {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        self.restarts.push_back(now);
+        if self.restarts.len() > self.max_restarts {
+            return Err(Error::TooManyRestarts);
+        }
+
+        let affected = match self.strategy {
+            RestartStrategy::OneForOne => exited.child..exited.child + 1,
+            RestartStrategy::OneForAll => 0..self.children.len(),
+            RestartStrategy::RestForOne => exited.child..self.children.len(),
+        };
+        for index in affected.clone().rev() {
+            self.stop_child(index, false)?;
+        }
+
+        let restart_at = now + self.backoff(self.restarts.len());
+        for child in &mut self.children[affected] {
+            child.restart_at = Some(restart_at);
+        }
+        Ok(())
+    }
+
+    fn backoff(&self, restarts: usize) -> Duration {
+        let doublings = restarts.saturating_sub(1).min(31) as u32;
+        self.min_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+
+    fn restart_due(&mut self, supervisor_address: &Address) -> Result<()> {
+        let now = Instant::now();
+        for index in 0..self.children.len() {
+            if self.children[index]
+                .restart_at
+                .is_some_and(|restart_at| restart_at <= now)
+            {
+                self.start_child(index, supervisor_address)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn start_child(&mut self, index: usize, supervisor_address: &Address) -> Result<()> {
+        let child = &mut self.children[index];
+        let inbox = match child.inbox.take() {
+            Some(inbox) => inbox,
+            None => rebind(&self.zmq_ctx, child.actor_ref.address())?,
+        };
+
+        child.generation += 1;
+        child.restart_at = None;
+        let inbox = inbox.with_generation(child.generation);
+
+        let run = Arc::clone(&child.run);
+        let generation = child.generation;
+        let zmq_ctx = self.zmq_ctx.clone();
+        let supervisor_address = supervisor_address.clone();
+        let address = child.actor_ref.address().clone();
+
+        let thread = std::thread::Builder::new()
+            .name(format!("actor {}", address))
+            .spawn(move || {
+                // Whatever state the child left half-updated is dropped with it
+                let failed = panic::catch_unwind(AssertUnwindSafe(|| run(inbox))).unwrap_or(true);
+
+                let exited = ChildExited {
+                    child: index,
+                    generation,
+                    failed,
+                };
+                // Nobody is left to tell if the supervisor is gone
+                let _ = Outbox::try_new(zmq_ctx, &supervisor_address, &address)
+                    .and_then(|outbox| outbox.try_send_message(&exited));
+            })?;
+
+        child.thread = Some(thread);
+        Ok(())
+    }
+
+    fn stop_child(&mut self, index: usize, drain: bool) -> Result<()> {
+        let child = &mut self.children[index];
+        let thread = match child.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        // A stop request sent to a socket that is already closed would wait
+        // in the outbox and reach the child after its restart. The child may
+        // close its socket right after the check, so the request only stops
+        // the generation it was meant for.
+        let stopped = if thread.is_finished() {
+            Ok(())
+        } else {
+            child
+                .actor_ref
+                .try_request_stop_generation(drain, child.generation)
+        };
+        if stopped.is_ok() {
+            let _ = thread.join();
+        }
+        stopped
+    }
+}
+
+impl Actor for Supervisor {
+    fn run(mut self, inbox: Inbox) {
+        Supervisor::run(&mut self, &inbox)
+    }
+}
+
+fn check_restartable(address_type: AddressType) -> Result<()> {
+    match address_type {
+        AddressType::Local => Err(Error::LocalChild),
+        AddressType::Remote | AddressType::Ipc => Ok(()),
+    }
+}
+
+fn rebind(zmq_ctx: &zmq::Context, address: &Address) -> Result<Inbox> {
+    let mut attempts_left = MAX_REBIND_ATTEMPTS;
+    loop {
+        attempts_left -= 1;
+        match Inbox::try_new(zmq_ctx.clone(), address) {
+            Err(Error::Transport(zmq::Error::EADDRINUSE)) if attempts_left > 0 => {
+                std::thread::sleep(REBIND_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RestartStrategy, Supervisor};
+    use crate::test_actors::{Count, CountHandler, Counter, Stats};
+    use crate::{ActorSystem, AddressType, Error, ErrorAction, Inbox, Outbox, ShouldTerminate};
+    use custom_derive::actor_message;
+    use serde::{Deserialize, Serialize};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // Runs a counter of its own on every start
+    fn child(stats: &Arc<Stats>) -> impl Fn(Inbox) + Send + Sync + 'static {
+        let stats = Arc::clone(stats);
+        move |inbox| Counter::new(inbox, &stats).run()
+    }
+
+    fn supervisor(ctx: &zmq::Context, strategy: RestartStrategy) -> Supervisor {
+        Supervisor::new(ctx.clone(), strategy)
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(10))
+    }
+
+    // What was sent before the crash may be lost with the old inbox, so we
+    // keep asking until the restarted child answers
+    fn wait_for_restart(outbox: &Outbox, inbox: &Inbox) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while outbox
+            .try_ask_via::<_, Count>(&Count::Total, inbox, Duration::from_millis(100))
+            .is_err()
+        {
+            assert!(Instant::now() < deadline, "Child was not restarted");
+        }
+    }
+
+    #[test]
+    fn restarted_child_keeps_its_address() {
+        let mut system = ActorSystem::new();
+        let mut supervisor = supervisor(system.context(), RestartStrategy::OneForOne);
+        let child = supervisor.add_child(AddressType::Ipc, child(&Arc::default()));
+        system.spawn(supervisor);
+
+        let inbox = Inbox::bind_new(system.context().clone(), AddressType::Local);
+        let outbox = child.outbox(inbox.address());
+        for _ in 0..3 {
+            outbox.send_message(&Count::Crash);
+            wait_for_restart(&outbox, &inbox);
+        }
+
+        system.shutdown();
+    }
+
+    // Crashes the middle one of three children and returns how often each was started
+    fn starts_after_crash(strategy: RestartStrategy, expected: &[usize]) -> Vec<usize> {
+        let mut system = ActorSystem::new();
+        let mut supervisor = supervisor(system.context(), strategy);
+
+        let stats: Vec<Arc<Stats>> = (0..3).map(|_| Arc::default()).collect();
+        let children: Vec<_> = stats
+            .iter()
+            .map(|stats| supervisor.add_child(AddressType::Ipc, child(stats)))
+            .collect();
+        system.spawn(supervisor);
+
+        let inbox = Inbox::bind_new(system.context().clone(), AddressType::Local);
+        children[1]
+            .outbox(inbox.address())
+            .send_message(&Count::Crash);
+
+        let current = || {
+            stats
+                .iter()
+                .map(|stats| stats.started.load(Ordering::SeqCst))
+                .collect::<Vec<_>>()
+        };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while current() != expected && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        system.shutdown();
+        current()
+    }
+
+    #[test]
+    fn restart_strategies() {
+        assert_eq!(
+            starts_after_crash(RestartStrategy::OneForOne, &[1, 2, 1]),
+            [1, 2, 1]
+        );
+        assert_eq!(
+            starts_after_crash(RestartStrategy::OneForAll, &[2, 2, 2]),
+            [2, 2, 2]
+        );
+        assert_eq!(
+            starts_after_crash(RestartStrategy::RestForOne, &[1, 2, 2]),
+            [1, 2, 2]
+        );
+    }
+
+    #[test]
+    fn gives_up_after_too_many_restarts() {
+        let ctx = zmq::Context::new();
+        let mut supervisor = supervisor(&ctx, RestartStrategy::OneForOne)
+            .with_restart_intensity(2, Duration::from_secs(60));
+
+        let starts = Arc::new(AtomicUsize::new(0));
+        let child_starts = Arc::clone(&starts);
+        supervisor.add_child(AddressType::Ipc, move |_inbox| {
+            child_starts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>("Cannot start")
+        });
+
+        let inbox = Inbox::bind_new(ctx, AddressType::Local);
+        assert!(matches!(
+            supervisor.try_run(&inbox),
+            Err(Error::TooManyRestarts)
+        ));
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+
+    #[actor_message(error = String)]
+    #[derive(Serialize, Deserialize, Clone)]
+    enum Job {
+        Fail,
+    }
+
+    struct Failing {
+        inbox: Inbox,
+    }
+
+    impl JobHandler for Failing {
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
+
+        fn handle_fail(&mut self) -> Result<ShouldTerminate, String> {
+            Err("Cannot do the job".to_owned())
+        }
+
+        fn on_error(&mut self, _err: String, _message: &Job) -> ErrorAction {
+            ErrorAction::Stop
+        }
+    }
+
+    #[test]
+    fn handler_errors_restart_children() {
+        let mut system = ActorSystem::new();
+        let mut supervisor = supervisor(system.context(), RestartStrategy::OneForOne);
+
+        let starts = Arc::new(AtomicUsize::new(0));
+        let child_starts = Arc::clone(&starts);
+        let child = supervisor.add_child(AddressType::Ipc, move |inbox| {
+            child_starts.fetch_add(1, Ordering::SeqCst);
+            Failing { inbox }.try_run()
+        });
+        system.spawn(supervisor);
+
+        // Messages sent before the restart may be lost with the old inbox
+        let inbox = Inbox::bind_new(system.context().clone(), AddressType::Local);
+        let outbox = child.outbox(inbox.address());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while starts.load(Ordering::SeqCst) < 2 {
+            assert!(Instant::now() < deadline, "Child was not restarted");
+            outbox.send_message(&Job::Fail);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        system.shutdown();
+    }
+
+    #[test]
+    fn local_children_are_rejected() {
+        let ctx = zmq::Context::new();
+        let mut supervisor = supervisor(&ctx, RestartStrategy::OneForOne);
+
+        assert!(matches!(
+            supervisor.try_add_child(AddressType::Local, child(&Arc::default())),
+            Err(Error::LocalChild)
+        ));
+        let address = crate::Address::new(AddressType::Local);
+        assert!(matches!(
+            supervisor.try_add_child_at(&address, child(&Arc::default())),
+            Err(Error::LocalChild)
+        ));
+        assert!(supervisor.is_empty());
+    }
+}
@@ -0,0 +1,351 @@
+// Owns the zmq context and the threads actors run on, so that callers do not
+// have to wire up addresses, inboxes and threads by hand.
+
+use crate::{Address, AddressType, Envelope, Inbox, Outbox, Result};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// How long a stop request may wait to be sent. Without any linger it would be
+// dropped whenever the ipc or tcp connection to the actor is not up yet, and
+// without a bound a request to an actor that closed its inbox in the meantime
+// would hold up the zmq context forever.
+const STOP_REQUEST_LINGER: Duration = Duration::from_secs(1);
+
+// How callers talk to a spawned actor
+#[derive(Clone)]
+pub struct ActorRef {
+    zmq_ctx: zmq::Context,
+    address: Address,
+}
+
+impl ActorRef {
+    pub(crate) fn new(zmq_ctx: zmq::Context, address: Address) -> Self {
+        Self { zmq_ctx, address }
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    // Replies to what is sent through the outbox go to source_address
+    pub fn outbox(&self, source_address: &Address) -> Outbox {
+        self.try_outbox(source_address)
+            .expect("Cannot create outbox")
+    }
+
+    pub fn try_outbox(&self, source_address: &Address) -> Result<Outbox> {
+        Outbox::try_new(self.zmq_ctx.clone(), &self.address, source_address)
+    }
+
+    // See Envelope::drain_request for what drain means
+    pub(crate) fn try_request_stop(&self, drain: bool) -> Result<()> {
+        self.try_send_stop_request(self.stop_request(drain))
+    }
+
+    // Stops only the generation of a supervised child that is bound with it,
+    // see Inbox::with_generation
+    pub(crate) fn try_request_stop_generation(&self, drain: bool, generation: u64) -> Result<()> {
+        self.try_send_stop_request(
+            self.stop_request(drain)
+                .try_with_stop_generation(generation)?,
+        )
+    }
+
+    fn stop_request(&self, drain: bool) -> Envelope {
+        if drain {
+            Envelope::drain_request(&self.address, &self.address)
+        } else {
+            Envelope::stop_request(&self.address, &self.address)
+        }
+    }
+
+    fn try_send_stop_request(&self, request: Envelope) -> Result<()> {
+        self.try_outbox(&self.address)?
+            .try_with_linger(Some(STOP_REQUEST_LINGER))?
+            .try_send_envelope(request)
+    }
+}
+
+// What an ActorSystem runs on a thread of its own, given the inbox it bound
+// for it. Closures taking the inbox are actors, which is how those generated
+// by #[actor_message] are spawned, e.g.
+// `system.spawn(|inbox| Worker { inbox }.run())`.
+pub trait Actor: Send + 'static {
+    fn run(self, inbox: Inbox);
+}
+
+impl<F> Actor for F
+where
+    F: FnOnce(Inbox) + Send + 'static,
+{
+    fn run(self, inbox: Inbox) {
+        self(inbox)
+    }
+}
+
+struct Spawned {
+    actor_ref: ActorRef,
+    thread: JoinHandle<()>,
+}
+
+pub struct ActorSystem {
+    zmq_ctx: zmq::Context,
+    actors: Vec<Spawned>,
+}
+
+impl Default for ActorSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ActorSystem {
+    pub fn new() -> Self {
+        Self::with_context(zmq::Context::new())
+    }
+
+    // For sharing the context with inboxes and outboxes made outside the system
+    pub fn with_context(zmq_ctx: zmq::Context) -> Self {
+        Self {
+            zmq_ctx,
+            actors: Vec::new(),
+        }
+    }
+
+    pub fn context(&self) -> &zmq::Context {
+        &self.zmq_ctx
+    }
+
+    // Binds a fresh local inbox and runs the actor with it on a thread of
+    // its own. Actors generated by #[actor_message] leave run when
+    // ActorSystem::shutdown asks them to.
+    pub fn spawn<A: Actor>(&mut self, actor: A) -> ActorRef {
+        self.try_spawn(actor).expect("Cannot spawn actor")
+    }
+
+    pub fn try_spawn<A: Actor>(&mut self, actor: A) -> Result<ActorRef> {
+        self.try_spawn_on(AddressType::Local, actor)
+    }
+
+    // Like spawn, with an inbox reachable from other processes or machines
+    pub fn spawn_on<A: Actor>(&mut self, address_type: AddressType, actor: A) -> ActorRef {
+        self.try_spawn_on(address_type, actor)
+            .expect("Cannot spawn actor")
+    }
+
+    pub fn try_spawn_on<A: Actor>(
+        &mut self,
+        address_type: AddressType,
+        actor: A,
+    ) -> Result<ActorRef> {
+        // Bound here rather than on the new thread, so that messages sent
+        // right after spawn returns are not lost
+        let inbox = Inbox::try_bind_new(self.zmq_ctx.clone(), address_type)?;
+        let actor_ref = ActorRef::new(self.zmq_ctx.clone(), inbox.address().clone());
+
+        let thread = std::thread::Builder::new()
+            .name(format!("actor {}", actor_ref.address))
+            .spawn(move || actor.run(inbox))?;
+
+        self.actors.push(Spawned {
+            actor_ref: actor_ref.clone(),
+            thread,
+        });
+        Ok(actor_ref)
+    }
+
+    pub fn len(&self) -> usize {
+        self.actors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actors.is_empty()
+    }
+
+    // Stops the actors one at a time, the last spawned first, and waits for
+    // each to leave its run loop before stopping the next. Messages still
+    // queued when the stop request arrives may or may not be handled.
+    // Panics if an actor did.
+    pub fn shutdown(mut self) {
+        self.try_shutdown().expect("Cannot shut down actors");
+    }
+
+    // The other actors are still stopped if sending one of them a stop
+    // request fails, the first error is returned
+    pub fn try_shutdown(&mut self) -> Result<()> {
+        self.try_stop_all(false)
+    }
+
+    // Like shutdown, but each actor first handles the messages that were
+    // queued in its inbox when its turn came. Those sent later by actors
+    // not stopped yet may still be lost.
+    pub fn drain(mut self) {
+        self.try_drain().expect("Cannot shut down actors");
+    }
+
+    pub fn try_drain(&mut self) -> Result<()> {
+        self.try_stop_all(true)
+    }
+
+    fn try_stop_all(&mut self, drain: bool) -> Result<()> {
+        let mut result = Ok(());
+
+        while let Some(spawned) = self.actors.pop() {
+            // Actors that left run already closed their inbox
+            let stopped = if spawned.thread.is_finished() {
+                Ok(())
+            } else {
+                spawned.actor_ref.try_request_stop(drain)
+            };
+            if stopped.is_ok() {
+                if let Err(panic) = spawned.thread.join() {
+                    std::panic::resume_unwind(panic);
+                }
+            }
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+
+        result
+    }
+}
+
+// Dropping the system shuts the actors down as well, but does not tell
+// about actors that panicked
+impl Drop for ActorSystem {
+    fn drop(&mut self) {
+        while let Some(spawned) = self.actors.pop() {
+            if spawned.thread.is_finished() || spawned.actor_ref.try_request_stop(false).is_ok() {
+                let _ = spawned.thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ActorSystem;
+    use crate::test_actors::{total, Count, CountHandler, Counter, Stats};
+    use crate::{AddressType, Inbox, ShouldTerminate};
+    use custom_derive::actor_message;
+    use serde::{Deserialize, Serialize};
+    use std::sync::atomic::Ordering;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn spawn_and_shut_down() {
+        let mut system = ActorSystem::new();
+        let stats = Arc::new(Stats::default());
+
+        let last_stage = system.spawn({
+            let stats = Arc::clone(&stats);
+            move |inbox| Counter::new(inbox, &stats).run()
+        });
+        let first_stage = system.spawn({
+            let stats = Arc::clone(&stats);
+            let last_stage = last_stage.clone();
+            move |inbox: Inbox| {
+                let next_stage = last_stage.outbox(inbox.address());
+                Counter::new(inbox, &stats)
+                    .with_next_stage(next_stage)
+                    .run()
+            }
+        });
+        assert_eq!(system.len(), 2);
+
+        let inbox = Inbox::bind_new(system.context().clone(), AddressType::Local);
+        let outbox = first_stage.outbox(inbox.address());
+        for i in 1..=10 {
+            outbox.send_message(&Count::Add(i));
+        }
+
+        // Wait until everything went through both stages
+        let last_stage_outbox = last_stage.outbox(inbox.address());
+        while total(&last_stage_outbox) < 55 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        system.shutdown();
+        // The first stage is stopped first and only forwarded
+        assert_eq!(*stats.stopped.lock().unwrap(), [0, 55]);
+    }
+
+    #[test]
+    fn drain_handles_queued_messages() {
+        let mut system = ActorSystem::new();
+        let stats = Arc::new(Stats::default());
+        let counter_ref = system.spawn({
+            let stats = Arc::clone(&stats);
+            move |inbox| Counter::new(inbox, &stats).run()
+        });
+
+        // Sending to a Local address queues the message right away
+        let inbox = Inbox::bind_new(system.context().clone(), AddressType::Local);
+        let outbox = counter_ref.outbox(inbox.address());
+        for i in 1..=100 {
+            outbox.send_message(&Count::Add(i));
+        }
+
+        system.drain();
+        assert_eq!(stats.added.load(Ordering::SeqCst), 5050);
+    }
+
+    #[test]
+    fn shut_down_actors_that_left_on_their_own() {
+        let mut system = ActorSystem::new();
+        let (left_sender, left) = mpsc::channel();
+        system.spawn(move |_inbox: Inbox| left_sender.send(()).unwrap());
+        left.recv().unwrap();
+
+        // Neither this nor dropping the context waits on the closed inbox
+        system.shutdown();
+    }
+
+    #[actor_message]
+    #[derive(Serialize, Deserialize)]
+    pub enum Nothing {
+        Never,
+    }
+
+    struct Idler {
+        inbox: Inbox,
+        stopped: mpsc::Sender<()>,
+    }
+
+    impl NothingHandler for Idler {
+        fn inbox(&self) -> &Inbox {
+            &self.inbox
+        }
+
+        fn idle_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(1))
+        }
+
+        fn post_stop(&mut self) {
+            self.stopped.send(()).unwrap();
+        }
+
+        fn handle_never(&mut self) -> ShouldTerminate {
+            ShouldTerminate::from(true)
+        }
+    }
+
+    #[test]
+    fn drop_stops_idle_actors() {
+        let (stopped_sender, stopped) = mpsc::channel();
+
+        {
+            let mut system = ActorSystem::new();
+            for _ in 0..3 {
+                let stopped = stopped_sender.clone();
+                system.spawn_on(AddressType::Ipc, move |inbox| {
+                    Idler { inbox, stopped }.run()
+                });
+            }
+        }
+
+        assert_eq!(stopped.try_iter().count(), 3);
+    }
+}
@@ -0,0 +1,123 @@
+// Actors shared by the tests of the modules running them, be it on threads
+// of their own, under a supervisor or on a scheduler
+
+use crate::{Context, Inbox, Outbox, Scheduled, ShouldTerminate};
+use custom_derive::actor_message;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[actor_message(context)]
+#[derive(Serialize, Deserialize)]
+pub enum Count {
+    Add(u64),
+    Total,
+    Crash,
+}
+
+// Shared by all counters of a test
+#[derive(Default)]
+pub struct Stats {
+    pub added: AtomicU64,
+    pub started: AtomicUsize,
+    // Totals of the counters that stopped, in the order they did
+    pub stopped: Mutex<Vec<u64>>,
+}
+
+pub struct Counter {
+    inbox: Inbox,
+    total: u64,
+    // Forwards additions instead of counting them if set
+    next_stage: Option<Outbox>,
+    // How long each addition takes
+    delay: Duration,
+    started: bool,
+    stopped: bool,
+    stats: Arc<Stats>,
+}
+
+impl Counter {
+    pub fn new(inbox: Inbox, stats: &Arc<Stats>) -> Self {
+        Self {
+            inbox,
+            total: 0,
+            next_stage: None,
+            delay: Duration::ZERO,
+            started: false,
+            stopped: false,
+            stats: Arc::clone(stats),
+        }
+    }
+
+    pub fn with_next_stage(mut self, next_stage: Outbox) -> Self {
+        self.next_stage = Some(next_stage);
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+impl CountHandler for Counter {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn pre_start(&mut self) {
+        assert!(!self.started, "Counter started twice");
+        self.started = true;
+        self.stats.started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn post_stop(&mut self) {
+        assert!(!self.stopped, "Counter stopped twice");
+        self.stopped = true;
+        self.stats.stopped.lock().unwrap().push(self.total);
+    }
+
+    fn handle_add(&mut self, _ctx: &Context, arg0: u64) -> ShouldTerminate {
+        std::thread::sleep(self.delay);
+        match &self.next_stage {
+            Some(next_stage) => next_stage.send_message(&Count::Add(arg0)),
+            None => {
+                self.total += arg0;
+                self.stats.added.fetch_add(arg0, Ordering::SeqCst);
+            }
+        }
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_total(&mut self, ctx: &Context) -> ShouldTerminate {
+        ctx.reply(&Count::Add(self.total));
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_crash(&mut self, _ctx: &Context) -> ShouldTerminate {
+        panic!("Asked to crash");
+    }
+}
+
+impl Scheduled for Counter {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn start(&mut self) {
+        self.pre_start();
+    }
+
+    fn turn(&mut self) -> Option<ShouldTerminate> {
+        self.run_turn()
+    }
+}
+
+// The total a counter answers Count::Total with
+pub fn total(outbox: &Outbox) -> u64 {
+    match outbox.ask(&Count::Total, Duration::from_secs(10)) {
+        Count::Add(total) => total,
+        _ => panic!("Expected a total"),
+    }
+}
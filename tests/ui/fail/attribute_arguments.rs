@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::actor_message;
+
+#[actor_message(Command)]
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    Stop,
+}
+
+fn main() {}
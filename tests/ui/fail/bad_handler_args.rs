@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::actor_message;
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    #[handler_args(spec)]
+    Job(String, u8),
+    #[handler_args(spec)]
+    Resize { width: u32 },
+    #[handler_args(spec, spec)]
+    Copy(String, String),
+    #[handler_args("spec")]
+    Delete(String),
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::actor_message;
+
+#[actor_message(context)]
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    Start { ctx: String },
+    #[handler_args(ctx)]
+    Stop(u8),
+}
+
+fn main() {}
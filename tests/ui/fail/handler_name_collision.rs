@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::actor_message;
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum Command {
+    StartJob,
+    Start_Job,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::actor_message;
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub struct Command {
+    id: u64,
+}
+
+fn main() {}
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::{actor_message, Inbox, ShouldTerminate};
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Job {
+    Run,
+}
+
+struct Worker {
+    inbox: Inbox,
+}
+
+// run would never call it, so it cannot be overridden
+impl JobHandler for Worker {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn receive(&self) -> Job {
+        Job::Run
+    }
+
+    fn handle_run(&mut self) -> ShouldTerminate {
+        ShouldTerminate::from(true)
+    }
+}
+
+fn main() {}
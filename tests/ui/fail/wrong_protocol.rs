@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::{actor_message, AddressType, Inbox, Outbox};
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Ping {
+    Ping,
+}
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Pong {
+    Pong,
+}
+
+fn main() {
+    let ctx = zmq::Context::new();
+    let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+    let outbox: Outbox<Ping> = Outbox::new(ctx, inbox.address(), inbox.address()).typed();
+
+    outbox.send_message(&Pong::Pong);
+}
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::{actor_message, AddressType, Inbox, Outbox, ShouldTerminate};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct JobSpec {
+    name: String,
+}
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    Stop,
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    Job(JobSpec),
+    #[handler_args(spec, priority)]
+    PriorityJob(JobSpec, u8),
+}
+
+struct Worker {
+    inbox: Inbox,
+    handled: Vec<String>,
+}
+
+impl CommandHandler for Worker {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn handle_stop(&mut self) -> ShouldTerminate {
+        self.handled.push("stop".to_owned());
+        ShouldTerminate::from(true)
+    }
+
+    fn handle_resize(&mut self, width: u32, height: u32) -> ShouldTerminate {
+        self.handled.push(format!("resize {}x{}", width, height));
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_job(&mut self, arg0: JobSpec) -> ShouldTerminate {
+        self.handled.push(format!("job {}", arg0.name));
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_priority_job(&mut self, spec: JobSpec, priority: u8) -> ShouldTerminate {
+        self.handled
+            .push(format!("job {} at {}", spec.name, priority));
+        ShouldTerminate::from(false)
+    }
+}
+
+fn main() {
+    let ctx = zmq::Context::new();
+    let mut worker = Worker {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        handled: Vec::new(),
+    };
+    let outbox = Outbox::new(ctx, worker.inbox.address(), worker.inbox.address());
+
+    let messages = vec![
+        Command::Resize {
+            width: 640,
+            height: 480,
+        },
+        Command::Job(JobSpec {
+            name: "build".to_owned(),
+        }),
+        Command::PriorityJob(
+            JobSpec {
+                name: "deploy".to_owned(),
+            },
+            7,
+        ),
+        Command::Stop,
+    ];
+
+    for message in &messages {
+        outbox.send_message(message);
+    }
+
+    // Stops after the last message
+    worker.run();
+
+    assert_eq!(
+        worker.handled,
+        vec!["resize 640x480", "job build", "job deploy at 7", "stop"]
+    );
+}
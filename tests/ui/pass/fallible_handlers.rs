@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::num::ParseIntError;
+use yocto_actor::{
+    actor_message, AddressType, Context, ErrorAction, Inbox, Outbox, ShouldTerminate,
+};
+
+#[actor_message(error = ParseIntError)]
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Command {
+    Add { number: String },
+    Stop,
+}
+
+struct Adder {
+    inbox: Inbox,
+    sum: u64,
+    restarts: usize,
+    failed: Vec<String>,
+}
+
+impl CommandHandler for Adder {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn handle_add(&mut self, number: String) -> Result<ShouldTerminate, ParseIntError> {
+        self.sum += number.parse::<u64>()?;
+        Ok(ShouldTerminate::from(false))
+    }
+
+    fn handle_stop(&mut self) -> Result<ShouldTerminate, ParseIntError> {
+        Ok(ShouldTerminate::from(true))
+    }
+
+    fn on_error(&mut self, _err: ParseIntError, message: &Command) -> ErrorAction {
+        if let Command::Add { number } = message {
+            self.failed.push(number.clone());
+        }
+
+        match self.failed.len() {
+            1 => ErrorAction::Continue,
+            2 => ErrorAction::Restart,
+            _ => ErrorAction::Stop,
+        }
+    }
+
+    fn on_restart(&mut self) {
+        self.sum = 0;
+        self.restarts += 1;
+    }
+}
+
+#[actor_message(context, error = String)]
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Query {
+    Ask(u8),
+}
+
+struct Answerer {
+    inbox: Inbox,
+}
+
+impl QueryHandler for Answerer {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn handle_ask(&mut self, ctx: &Context, arg0: u8) -> Result<ShouldTerminate, String> {
+        ctx.try_reply(&Query::Ask(arg0 + 1))
+            .map_err(|err| err.to_string())?;
+        Ok(ShouldTerminate::from(true))
+    }
+
+    fn on_error(&mut self, err: String, _message: &Query) -> ErrorAction {
+        panic!("{}", err)
+    }
+}
+
+fn main() {
+    let ctx = zmq::Context::new();
+    let mut adder = Adder {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        sum: 0,
+        restarts: 0,
+        failed: Vec::new(),
+    };
+    let outbox = Outbox::new(ctx.clone(), adder.inbox.address(), adder.inbox.address());
+
+    for number in &["1", "one", "2", "two", "3", "three", "4"] {
+        outbox.send_message(&Command::Add {
+            number: number.to_string(),
+        });
+    }
+
+    // Stops on the third failure, before the last Add
+    adder.run();
+    assert_eq!(adder.failed, vec!["one", "two", "three"]);
+    assert_eq!(adder.restarts, 1);
+    assert_eq!(adder.sum, 3);
+
+    let mut answerer = Answerer {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+    };
+    let outbox = Outbox::new(ctx, answerer.inbox.address(), adder.inbox.address());
+    let answerer_thread = std::thread::spawn(move || answerer.run());
+
+    let reply: Query = outbox.ask(&Query::Ask(1), std::time::Duration::from_secs(10));
+    assert!(matches!(reply, Query::Ask(2)));
+    answerer_thread.join().unwrap();
+}
@@ -0,0 +1,96 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use yocto_actor::{actor_message, AddressType, Inbox, Message, Outbox, ShouldTerminate};
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum KvCommand<K, V>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+{
+    Put { key: K, value: V },
+    Remove(K),
+    Clear,
+}
+
+struct KvStore<K, V> {
+    inbox: Inbox,
+    entries: BTreeMap<K, V>,
+}
+
+impl<K, V> KvCommandHandler<K, V> for KvStore<K, V>
+where
+    K: Ord + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn handle_put(&mut self, key: K, value: V) -> ShouldTerminate {
+        self.entries.insert(key, value);
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_remove(&mut self, arg0: K) -> ShouldTerminate {
+        self.entries.remove(&arg0);
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_clear(&mut self) -> ShouldTerminate {
+        self.entries.clear();
+        ShouldTerminate::from(true)
+    }
+}
+
+#[actor_message]
+#[derive(Serialize)]
+pub enum Borrowed<'a, T: Serialize> {
+    Name { name: &'a str },
+    Value(T),
+}
+
+fn assert_message<M: Message>(_message: &M) {}
+
+fn main() {
+    let ctx = zmq::Context::new();
+    let mut store: KvStore<String, u64> = KvStore {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        entries: BTreeMap::new(),
+    };
+    let outbox = Outbox::new(ctx, store.inbox.address(), store.inbox.address());
+
+    outbox.send_message(&KvCommand::Put {
+        key: "tenant".to_owned(),
+        value: 1u64,
+    });
+    outbox.send_message(&KvCommand::<String, u64>::Put {
+        key: "region".to_owned(),
+        value: 2,
+    });
+    outbox.send_message(&KvCommand::<String, u64>::Remove("tenant".to_owned()));
+
+    for _ in 0..3 {
+        let (message, _) = store.receive_with_headers();
+        store.dispatch_message(message);
+    }
+    assert_eq!(store.entries.len(), 1);
+    assert_eq!(store.entries["region"], 2);
+
+    outbox.send_message(&KvCommand::<String, u64>::Clear);
+    store.run();
+    assert!(store.entries.is_empty());
+
+    let name: Borrowed<u8> = Borrowed::Name { name: "acme" };
+    assert_message(&name);
+    assert_message(&Borrowed::Value(7u8));
+
+    // Every instance has a tag of its own, or they would decode as one another
+    assert_ne!(
+        KvCommand::<String, u64>::type_tag(),
+        KvCommand::<u32, u32>::type_tag()
+    );
+    assert_ne!(Borrowed::<u8>::type_tag(), Borrowed::<u16>::type_tag());
+}
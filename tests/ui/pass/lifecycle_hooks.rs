@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::{actor_message, AddressType, ErrorAction, Inbox, Outbox, ShouldTerminate};
+
+#[actor_message]
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Job {
+    Run { name: String },
+    Quit,
+}
+
+// Logs every hook, handlers do nothing but terminate on Quit
+struct Logger {
+    inbox: Inbox,
+    log: Vec<String>,
+}
+
+impl JobHandler for Logger {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn pre_start(&mut self) {
+        self.log.push("pre_start".to_owned());
+    }
+
+    fn before_dispatch(&mut self, message: &Job) {
+        self.log.push(format!("before {:?}", message));
+    }
+
+    fn after_dispatch(&mut self, variant: &'static str, outcome: &ShouldTerminate) {
+        self.log.push(format!("after {} {:?}", variant, outcome));
+    }
+
+    fn post_stop(&mut self) {
+        self.log.push("post_stop".to_owned());
+    }
+
+    fn handle_run(&mut self, _name: String) -> ShouldTerminate {
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_quit(&mut self) -> ShouldTerminate {
+        ShouldTerminate::from(true)
+    }
+}
+
+#[actor_message(error = String)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Step {
+    Fail,
+    Done,
+}
+
+struct Restarter {
+    inbox: Inbox,
+    log: Vec<String>,
+}
+
+impl StepHandler for Restarter {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn after_dispatch(&mut self, variant: &'static str, outcome: &Result<ShouldTerminate, String>) {
+        self.log
+            .push(format!("after {} {}", variant, outcome.is_ok()));
+    }
+
+    fn on_error(&mut self, _err: String, _message: &Step) -> ErrorAction {
+        ErrorAction::Restart
+    }
+
+    fn pre_restart(&mut self, err: &String) {
+        self.log.push(format!("pre_restart {}", err));
+    }
+
+    fn on_restart(&mut self) {
+        self.log.push("on_restart".to_owned());
+    }
+
+    fn post_restart(&mut self) {
+        self.log.push("post_restart".to_owned());
+    }
+
+    fn handle_fail(&mut self) -> Result<ShouldTerminate, String> {
+        Err("failed".to_owned())
+    }
+
+    fn handle_done(&mut self) -> Result<ShouldTerminate, String> {
+        Ok(ShouldTerminate::from(true))
+    }
+}
+
+fn main() {
+    let ctx = zmq::Context::new();
+
+    let mut logger = Logger {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        log: Vec::new(),
+    };
+    let outbox = Outbox::new(ctx.clone(), logger.inbox.address(), logger.inbox.address());
+    outbox.send_message(&Job::Run {
+        name: "build".to_owned(),
+    });
+    outbox.send_message(&Job::Quit);
+    logger.run();
+    assert_eq!(
+        logger.log,
+        vec![
+            "pre_start",
+            "before Run { name: \"build\" }",
+            "after Run ShouldTerminate(false)",
+            "before Quit",
+            "after Quit ShouldTerminate(true)",
+            "post_stop",
+        ]
+    );
+
+    let mut restarter = Restarter {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        log: Vec::new(),
+    };
+    let outbox = Outbox::new(ctx, restarter.inbox.address(), restarter.inbox.address());
+    outbox.send_message(&Step::Fail);
+    outbox.send_message(&Step::Done);
+    restarter.run();
+    assert_eq!(
+        restarter.log,
+        vec![
+            "after Fail false",
+            "pre_restart failed",
+            "on_restart",
+            "post_restart",
+            "after Done true",
+        ]
+    );
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use yocto_actor::{actor_message, AddressType, Inbox, Outbox, ShouldBlock, ShouldTerminate};
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    Stop,
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    #[handler_args(name)]
+    Job(String),
+}
+
+struct Worker {
+    inbox: Inbox,
+    handled: Vec<String>,
+}
+
+impl CommandHandler for Worker {
+    fn inbox(&self) -> &Inbox {
+        &self.inbox
+    }
+
+    fn handle_stop(&mut self) -> ShouldTerminate {
+        self.handled.push("stop".to_owned());
+        ShouldTerminate::from(true)
+    }
+
+    fn handle_resize(&mut self, width: u32, height: u32) -> ShouldTerminate {
+        self.handled.push(format!("resize {}x{}", width, height));
+        ShouldTerminate::from(false)
+    }
+
+    fn handle_job(&mut self, name: String) -> ShouldTerminate {
+        self.handled.push(format!("job {}", name));
+        ShouldTerminate::from(false)
+    }
+}
+
+#[actor_message]
+#[derive(Serialize, Deserialize)]
+pub enum Store<T: Serialize> {
+    Put(T),
+    Clear,
+}
+
+fn main() {
+    let ctx = zmq::Context::new();
+    let mut worker = Worker {
+        inbox: Inbox::bind_new(ctx.clone(), AddressType::Local),
+        handled: Vec::new(),
+    };
+    let client = CommandClient::from(
+        Outbox::new(ctx.clone(), worker.inbox.address(), worker.inbox.address()).typed(),
+    );
+
+    client.send_resize(640, 480);
+    client.send_job("build".to_owned());
+    client.outbox().send_message(&Command::Stop);
+
+    worker.run();
+
+    assert_eq!(worker.handled, vec!["resize 640x480", "job build", "stop"]);
+
+    // The client of a generic enum is generic as well
+    let inbox = Inbox::bind_new(ctx.clone(), AddressType::Local);
+    let store: StoreClient<u64> =
+        StoreClient::new(Outbox::new(ctx, inbox.address(), inbox.address()).typed());
+    store.send_put(42);
+    store.send_clear();
+
+    let envelope = inbox.receive_envelope(ShouldBlock::from(true)).unwrap();
+    assert!(matches!(envelope.decode(), Store::Put(42u64)));
+    let envelope = inbox.receive_envelope(ShouldBlock::from(true)).unwrap();
+    assert!(matches!(envelope.decode::<Store<u64>>(), Store::Clear));
+}